@@ -0,0 +1,107 @@
+//! Speed of programs dominated by the instruction sequences the compiler fuses into
+//! superinstructions, compiled with and without them.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use raven_lang::{CompileOptions, VirtualMachine};
+
+const RUNS: u32 = 20;
+
+/// Workloads, each a name and a program
+const PROGRAMS: [(&str, &str); 4] = [
+    (
+        "counting loop",
+        "
+function count(n) {
+    let mutable i = 0
+    while i < n {
+        i += 1
+    }
+    return i
+}
+count(300000)
+",
+    ),
+    (
+        "accumulate",
+        "
+function sum(n) {
+    let mutable i = 0
+    let mutable total = 0
+    while i < n {
+        total = total + 3
+        i = i + 1
+    }
+    return total
+}
+sum(200000)
+",
+    ),
+    (
+        "nested loops",
+        "
+function grid(n) {
+    let mutable hits = 0
+    let mutable x = 0
+    while x < n {
+        let mutable y = 0
+        while y < n {
+            if x == y { hits += 1 }
+            y += 1
+        }
+        x += 1
+    }
+    return hits
+}
+grid(400)
+",
+    ),
+    (
+        "string building",
+        "
+function build(n) {
+    let mutable i = 0
+    let mutable s = \"\"
+    while i < n {
+        s = \"<\" + \"x\" + \">\"
+        i += 1
+    }
+    return s
+}
+build(100000)
+",
+    ),
+];
+
+/// Fastest of `RUNS` runs compiling and running the program, which is less noisy
+/// than the mean
+fn time(source: &str, options: CompileOptions) -> Duration {
+    let mut vm = VirtualMachine::new();
+    vm.set_compile_options(options);
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        black_box(vm.interpret(black_box(source))).expect("benchmark program failed");
+        best = best.min(start.elapsed());
+    }
+    best
+}
+
+fn main() {
+    let plain = CompileOptions {
+        superinstructions: false,
+    };
+    let fused = CompileOptions::default();
+    for (name, source) in PROGRAMS {
+        let before = time(source, plain);
+        let after = time(source, fused);
+        println!(
+            "{}: {:.2?} without superinstructions, {:.2?} with, {:.2}x faster",
+            name,
+            before,
+            after,
+            before.as_secs_f64() / after.as_secs_f64()
+        );
+    }
+}
@@ -1,12 +1,20 @@
 use std::ops::Index;
 
-use crate::{ConstantPool, Value};
+use crate::{ConstantPool, Span, Value};
 
-pub type Chunk = usize;
+/// One byte of the instruction stream
+pub type Chunk = u8;
 
-/// The first byte is reserved for the OpCode that the rest is for operands
-#[repr(usize)]
-#[derive(PartialEq, Eq, Clone, Copy)]
+/// Largest operand that fits in two chunks
+pub const MAX_SHORT: usize = u16::MAX as usize;
+/// Largest operand that fits in three chunks
+pub const MAX_LONG: usize = (1 << 24) - 1;
+
+/// The first byte is reserved for the OpCode that the rest is for operands. Operands
+/// are one chunk unless stated otherwise, wider ones are stored big-endian. Constant
+/// indices are three chunks, except for `Constant` whose index is one.
+#[repr(u8)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum OpCode {
     Constant = 0,
     Add,
@@ -15,6 +23,88 @@ pub enum OpCode {
     Divide,
     Negate = 5,
     Return = 6,
+    Nil,
+    True,
+    False,
+    Equal,
+    Greater,
+    Less,
+    Not,
+    Pop,
+    /// Operand is the forward distance to jump, two chunks
+    Jump,
+    /// Jumps forward if the top of the stack is falsey without popping it
+    JumpIfFalse,
+    /// Replace the top of the stack with its string representation
+    ToString,
+    /// Operand is the constant index of the variable name
+    DefineGlobal,
+    DefineMutableGlobal,
+    GetGlobal,
+    SetGlobal,
+    /// Operand is the stack slot of the variable
+    GetLocal,
+    SetLocal,
+    /// Operand is the backward distance to jump, two chunks
+    Loop,
+    /// Operand is the number of elements on the stack, two chunks
+    BuildList,
+    /// Operand is the number of key value pairs on the stack, two chunks
+    BuildMap,
+    GetIndex,
+    SetIndex,
+    Range,
+    RangeInclusive,
+    /// Replace the top of the stack with an iterator over it
+    GetIter,
+    /// Push the next item of the iterator on top of the stack, or jump forward by the
+    /// two chunk operand once it is exhausted
+    ForIter,
+    /// Operand is the number of arguments on the stack above the callee
+    Call,
+    /// Operand is the constant index of the function, followed by an `is_local` and
+    /// `index` operand pair for every variable it captures
+    Closure,
+    /// Operand is the index into the current closure's upvalues
+    GetUpvalue,
+    SetUpvalue,
+    /// Move the local on top of the stack to the heap for the closures capturing it
+    CloseUpvalue,
+    /// Operand is the constant index of the struct name
+    Struct,
+    /// Add a field to the struct on top of the stack. Operands are the constant index
+    /// of the field name and whether it is public.
+    Field,
+    /// Pop a closure and add it to the struct below it. Operands are the same as for
+    /// `Field`.
+    Method,
+    /// Operand is the constant index of the property name
+    GetProperty,
+    SetProperty,
+    /// Pop a struct and a value and push whether the value is an instance of the struct
+    InstanceOf,
+    /// Pop the end, start and value and push whether the value is a number in the
+    /// range. Operand is whether the end is inclusive.
+    InRange,
+    /// Raise a runtime error for the value on top of the stack not matching any arm
+    NoMatch,
+    /// Operand is the constant index of the variable name. Makes the global visible to
+    /// modules importing this one.
+    Export,
+    /// Operand is the constant index of a module. Pushes the module followed by the
+    /// result of running its top-level code, or nil if that has already run.
+    Import,
+    /// `Constant` for pools too large for its index to fit in a chunk
+    ConstantLong,
+    /// `Constant` followed by `Add`. Operand is the constant index.
+    AddConstant,
+    /// `GetLocal`, `AddConstant` and `SetLocal` of the same slot. Operands are the
+    /// constant index and the slot.
+    IncrementLocal,
+    /// `Less` followed by `JumpIfFalse`. Operand is the forward distance to jump.
+    LessJumpIfFalse,
+    GreaterJumpIfFalse,
+    EqualJumpIfFalse,
 }
 
 impl From<OpCode> for Chunk {
@@ -34,15 +124,83 @@ impl TryFrom<Chunk> for OpCode {
             4 => Ok(OpCode::Divide),
             5 => Ok(OpCode::Negate),
             6 => Ok(OpCode::Return),
+            7 => Ok(OpCode::Nil),
+            8 => Ok(OpCode::True),
+            9 => Ok(OpCode::False),
+            10 => Ok(OpCode::Equal),
+            11 => Ok(OpCode::Greater),
+            12 => Ok(OpCode::Less),
+            13 => Ok(OpCode::Not),
+            14 => Ok(OpCode::Pop),
+            15 => Ok(OpCode::Jump),
+            16 => Ok(OpCode::JumpIfFalse),
+            17 => Ok(OpCode::ToString),
+            18 => Ok(OpCode::DefineGlobal),
+            19 => Ok(OpCode::DefineMutableGlobal),
+            20 => Ok(OpCode::GetGlobal),
+            21 => Ok(OpCode::SetGlobal),
+            22 => Ok(OpCode::GetLocal),
+            23 => Ok(OpCode::SetLocal),
+            24 => Ok(OpCode::Loop),
+            25 => Ok(OpCode::BuildList),
+            26 => Ok(OpCode::BuildMap),
+            27 => Ok(OpCode::GetIndex),
+            28 => Ok(OpCode::SetIndex),
+            29 => Ok(OpCode::Range),
+            30 => Ok(OpCode::RangeInclusive),
+            31 => Ok(OpCode::GetIter),
+            32 => Ok(OpCode::ForIter),
+            33 => Ok(OpCode::Call),
+            34 => Ok(OpCode::Closure),
+            35 => Ok(OpCode::GetUpvalue),
+            36 => Ok(OpCode::SetUpvalue),
+            37 => Ok(OpCode::CloseUpvalue),
+            38 => Ok(OpCode::Struct),
+            39 => Ok(OpCode::Field),
+            40 => Ok(OpCode::Method),
+            41 => Ok(OpCode::GetProperty),
+            42 => Ok(OpCode::SetProperty),
+            43 => Ok(OpCode::InstanceOf),
+            44 => Ok(OpCode::InRange),
+            45 => Ok(OpCode::NoMatch),
+            46 => Ok(OpCode::Export),
+            47 => Ok(OpCode::Import),
+            48 => Ok(OpCode::ConstantLong),
+            49 => Ok(OpCode::AddConstant),
+            50 => Ok(OpCode::IncrementLocal),
+            51 => Ok(OpCode::LessJumpIfFalse),
+            52 => Ok(OpCode::GreaterJumpIfFalse),
+            53 => Ok(OpCode::EqualJumpIfFalse),
             _ => Err(()),
         }
     }
 }
 
+/// Where in the source an instruction was compiled from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SourceSpan {
+    /// Byte offset and length in the source
+    pub offset: u32,
+    pub length: u32,
+    /// Counted in chars from 1
+    pub column: u32,
+}
+
+impl From<SourceSpan> for Span {
+    fn from(span: SourceSpan) -> Self {
+        let start = span.offset as usize;
+        Span::new(start, start + span.length as usize)
+    }
+}
+
 pub struct ByteCode {
     chunks: Vec<Chunk>,
     constants: ConstantPool,
-    lines: Vec<usize>,
+    /// Index of the first chunk of each run of chunks on the same line, with the line
+    lines: Vec<(usize, usize)>,
+    /// Index of the first chunk of each run of chunks compiled from the same source,
+    /// with its span
+    spans: Vec<(usize, SourceSpan)>,
 }
 
 impl ByteCode {
@@ -51,18 +209,85 @@ impl ByteCode {
             chunks: Vec::new(),
             constants: ConstantPool::new(),
             lines: Vec::new(),
+            spans: Vec::new(),
+        }
+    }
+
+    /// Reassemble bytecode from its instruction stream, constants and the runs of
+    /// its line and span tables, as returned by `get_line_runs` and `get_span_runs`
+    pub fn from_parts(
+        chunks: Vec<Chunk>,
+        constants: Vec<Value>,
+        lines: Vec<(usize, usize)>,
+        spans: Vec<(usize, SourceSpan)>,
+    ) -> Self {
+        let mut pool = ConstantPool::new();
+        for constant in constants {
+            pool.push_value(constant);
+        }
+        Self {
+            chunks,
+            constants: pool,
+            lines,
+            spans,
         }
     }
 
+    /// Add a chunk that has the same span as the one before it, if any
     pub fn push_chunk(&mut self, chunk: Chunk, line: usize) {
+        if self.lines.last().map(|(_, last)| *last) != Some(line) {
+            self.lines.push((self.chunks.len(), line));
+        }
         self.chunks.push(chunk);
-        self.lines.push(line);
+    }
+
+    pub fn push_spanned_chunk(&mut self, chunk: Chunk, line: usize, span: SourceSpan) {
+        if self.spans.last().map(|(_, last)| *last) != Some(span) {
+            self.spans.push((self.chunks.len(), span));
+        }
+        self.push_chunk(chunk, line);
     }
 
     pub fn get_chunk(&self, index: usize) -> Option<&Chunk> {
         self.chunks.get(index)
     }
 
+    /// The two chunk operand starting at `index`
+    pub fn get_short(&self, index: usize) -> Option<usize> {
+        let bytes = self.chunks.get(index..index + 2)?;
+        Some(usize::from(bytes[0]) << 8 | usize::from(bytes[1]))
+    }
+
+    /// The three chunk operand starting at `index`
+    pub fn get_long(&self, index: usize) -> Option<usize> {
+        let bytes = self.chunks.get(index..index + 3)?;
+        Some(usize::from(bytes[0]) << 16 | usize::from(bytes[1]) << 8 | usize::from(bytes[2]))
+    }
+
+    /// Overwrite an already emitted chunk
+    pub fn set_chunk(&mut self, index: usize, chunk: Chunk) {
+        self.chunks[index] = chunk;
+    }
+
+    /// Overwrite an already emitted two chunk operand, used to backpatch jumps
+    pub fn set_short(&mut self, index: usize, operand: usize) {
+        self.chunks[index] = (operand >> 8) as Chunk;
+        self.chunks[index + 1] = operand as Chunk;
+    }
+
+    /// Drop the chunks from `length` on, used to replace the last instructions with a
+    /// superinstruction
+    pub fn truncate(&mut self, length: usize) {
+        self.chunks.truncate(length);
+        // Runs are ordered, so only the last few can start past the end
+        while self.lines.last().is_some_and(|(start, _)| *start >= length) {
+            self.lines.pop();
+        }
+        while self.spans.last().is_some_and(|(start, _)| *start >= length) {
+            self.spans.pop();
+        }
+    }
+
     pub fn get_chunks(&self) -> &Vec<Chunk> {
         &self.chunks
     }
@@ -79,8 +304,38 @@ impl ByteCode {
         self.constants.get(index)
     }
 
+    pub fn get_constants(&self) -> &[Value] {
+        self.constants.values()
+    }
+
     pub fn get_line(&self, index: usize) -> Option<&usize> {
-        self.lines.get(index)
+        self.run_at(&self.lines, index)
+    }
+
+    /// Index of the first chunk of each run of chunks on the same line, with the line
+    pub fn get_line_runs(&self) -> &[(usize, usize)] {
+        &self.lines
+    }
+
+    /// Index of the first chunk of each run of chunks with the same span, with the span
+    pub fn get_span_runs(&self) -> &[(usize, SourceSpan)] {
+        &self.spans
+    }
+
+    pub fn get_span(&self, index: usize) -> Option<SourceSpan> {
+        self.run_at(&self.spans, index).copied()
+    }
+
+    /// Value of the run the chunk at `index` belongs to
+    fn run_at<'a, T>(&self, runs: &'a [(usize, T)], index: usize) -> Option<&'a T> {
+        if index >= self.chunks.len() {
+            return None;
+        }
+        let run = runs.partition_point(|(start, _)| *start <= index);
+        match run {
+            0 => None,
+            _ => Some(&runs[run - 1].1),
+        }
     }
 }
 
@@ -92,7 +347,15 @@ impl Default for ByteCode {
 
 impl Index<usize> for ByteCode {
     type Output = usize;
+    /// The line of the chunk at `index`, panicking if there is no such chunk
     fn index(&self, index: usize) -> &Self::Output {
-        &self.lines[index]
+        match self.get_line(index) {
+            Some(line) => line,
+            None => panic!(
+                "chunk index {} out of range for {} chunks",
+                index,
+                self.chunks.len()
+            ),
+        }
     }
 }
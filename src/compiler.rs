@@ -1,27 +1,2155 @@
-use crate::{InterpretError, Scanner, TokenType};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-pub fn compile(source: &str) -> Result<(), InterpretError> {
-    let mut scanner = Scanner::new(source);
-    let mut line: usize = 0;
-    loop {
-        match scanner.get_token() {
-            Ok(token) => {
-                if token.line != line {
-                    print!("{:4} ", token.line);
-                    line = token.line;
-                } else {
-                    print!("   | ");
+use crate::{
+    unescape, ByteCode, Chunk, Diagnostic, Heap, ModuleLoader, ObjFunction, ObjModule, ObjRef,
+    Object, OpCode, Scanner, SourceSpan, Span, Token, TokenType, Value, MAX_LONG, MAX_SHORT,
+};
+
+#[cfg(feature = "debug_print_code")]
+use crate::disassembler;
+
+#[derive(PartialEq, PartialOrd, Clone, Copy)]
+enum Precedence {
+    None,
+    Assignment, // =
+    Or,         // or
+    And,        // and
+    Equality,   // == !=
+    Comparison, // < > <= >=
+    Range,      // .. ..=
+    Term,       // + -
+    Factor,     // * /
+    Unary,      // not -
+    Call,       // . () []
+    Primary,
+}
+
+impl Precedence {
+    fn next(self) -> Self {
+        match self {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Range,
+            Precedence::Range => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Call,
+            Precedence::Call => Precedence::Primary,
+            Precedence::Primary => Precedence::Primary,
+        }
+    }
+}
+
+/// The flag tells the parse function whether an assignment may follow
+type ParseFn<'a> = fn(&mut Compiler<'a>, bool) -> Result<(), Diagnostic>;
+
+struct ParseRule<'a> {
+    prefix: Option<ParseFn<'a>>,
+    infix: Option<ParseFn<'a>>,
+    precedence: Precedence,
+}
+
+impl<'a> ParseRule<'a> {
+    fn new(
+        prefix: Option<ParseFn<'a>>,
+        infix: Option<ParseFn<'a>>,
+        precedence: Precedence,
+    ) -> Self {
+        Self {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+}
+
+/// Most locals a single scope chain may hold at once
+const MAX_LOCALS: usize = 256;
+/// Most parameters a function may declare, and arguments a call may pass
+const MAX_ARITY: usize = 255;
+/// Most variables a single function may capture from the functions enclosing it
+const MAX_UPVALUES: usize = 256;
+
+struct Local<'a> {
+    name: &'a str,
+    /// Stack slot relative to the frame. Usually the local's index, but temporaries of
+    /// an unfinished expression can sit below locals declared by a `match`.
+    slot: usize,
+    /// Scope depth, `None` until the initializer has been compiled
+    depth: Option<usize>,
+    mutable: bool,
+    /// Whether a closure refers to it, in which case it is moved to the heap when it
+    /// goes out of scope instead of being discarded
+    captured: bool,
+}
+
+/// A variable captured from an enclosing function
+struct Upvalue {
+    /// Stack slot of the enclosing function's local when `is_local`, otherwise the
+    /// index of one of the enclosing function's own upvalues
+    index: usize,
+    is_local: bool,
+    mutable: bool,
+}
+
+/// Jump targets for `break` and `continue` inside the innermost loop
+struct Loop {
+    /// Where `continue` jumps back to
+    start: usize,
+    /// Locals declared deeper than this are popped when leaving the loop early
+    scope_depth: usize,
+    /// `break` jumps waiting to be patched to the end of the loop
+    breaks: Vec<usize>,
+}
+
+/// Pattern of a `match` arm
+enum Pattern<'a> {
+    /// `_` matches anything without binding it
+    Wildcard,
+    /// A name matches anything and binds it
+    Binding(&'a str),
+    /// Compared with `==`
+    Literal(Value),
+    Range {
+        start: f64,
+        end: f64,
+        inclusive: bool,
+    },
+    /// `Name { field: pattern, other }`. A field without a pattern binds its value.
+    Struct {
+        name: Token<'a>,
+        fields: Vec<(&'a str, Pattern<'a>)>,
+    },
+}
+
+impl Pattern<'_> {
+    /// Whether every value matches the pattern
+    fn is_irrefutable(&self) -> bool {
+        matches!(self, Pattern::Wildcard | Pattern::Binding(_))
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum FunctionKind {
+    Script,
+    Function,
+    /// Declared in a struct, its slot zero holds `self`
+    Method,
+}
+
+/// How far compiling had got before a declaration, to return to when the declaration
+/// has an error
+struct Checkpoint {
+    /// Number of enclosing functions
+    functions: usize,
+    scope_depth: usize,
+    locals: usize,
+    loops: usize,
+    temporaries: usize,
+    open_braces: usize,
+}
+
+/// State of a function whose body is being compiled
+struct FunctionState<'a> {
+    kind: FunctionKind,
+    name: &'a str,
+    arity: usize,
+    bytecode: ByteCode,
+    /// Locals in declaration order
+    locals: Vec<Local<'a>>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+    /// Values an unfinished expression has left on the stack above the locals, such as
+    /// the left operand while the right one is compiled
+    temporaries: usize,
+    loops: Vec<Loop>,
+    /// Offset of every instruction emitted, so the last few can be fused
+    instructions: Vec<usize>,
+    /// Offset of the latest instruction a jump lands on, which can't be fused with
+    /// the instructions before it
+    jump_target: usize,
+}
+
+impl<'a> FunctionState<'a> {
+    fn new(kind: FunctionKind, name: &'a str) -> Self {
+        Self {
+            kind,
+            name,
+            arity: 0,
+            bytecode: ByteCode::new(),
+            // Slot zero holds the function being called, or the receiver for methods
+            locals: vec![Local {
+                name: match kind {
+                    FunctionKind::Method => "self",
+                    _ => "",
+                },
+                slot: 0,
+                depth: Some(0),
+                mutable: false,
+                captured: false,
+            }],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            temporaries: 0,
+            loops: Vec::new(),
+            instructions: Vec::new(),
+            jump_target: 0,
+        }
+    }
+}
+
+struct Compiler<'a> {
+    source: &'a str,
+    scanner: Scanner<'a>,
+    current: Token<'a>,
+    previous: Token<'a>,
+    /// Braces consumed so far that haven't been closed
+    open_braces: usize,
+    /// Where string constants and functions are allocated
+    heap: &'a mut Heap,
+    /// The innermost function being compiled
+    function: FunctionState<'a>,
+    /// Functions whose bodies contain the current one, innermost last
+    enclosing: Vec<FunctionState<'a>>,
+    /// Mutability of the globals declared so far, used to reject assignments early
+    globals: HashMap<&'a str, bool>,
+    /// Warnings so far, and the diagnostics of the modules this file imports
+    diagnostics: Vec<Diagnostic>,
+    /// Compiles the files this one imports
+    loader: &'a mut ModuleLoader,
+    /// File being compiled, `None` for code that isn't in a file
+    file: Option<PathBuf>,
+    /// Constants of the files whose compilation is waiting on this one's, which have to
+    /// survive garbage collection
+    outer_roots: Vec<Value>,
+    /// Strings in the pattern of the match arm being compiled, which only become
+    /// constants once the arm's tests are emitted
+    pattern_literals: Vec<Value>,
+    options: CompileOptions,
+}
+
+/// Choices about the code the compiler emits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompileOptions {
+    /// Fuse common sequences of instructions into superinstructions that do the same
+    /// work in one dispatch
+    pub superinstructions: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            superinstructions: true,
+        }
+    }
+}
+
+/// Compile a program into the function that runs its top-level code, along with any
+/// warnings. On failure every diagnostic found is returned, errors and warnings alike.
+/// Imports are resolved relative to the working directory.
+pub fn compile(
+    source: &str,
+    heap: &mut Heap,
+) -> Result<(ObjRef, Vec<Diagnostic>), Vec<Diagnostic>> {
+    compile_with_imports(source, heap, &mut ModuleLoader::default())
+}
+
+/// Compile the main script of a program, compiling the modules it imports with `loader`
+pub fn compile_with_imports(
+    source: &str,
+    heap: &mut Heap,
+    loader: &mut ModuleLoader,
+) -> Result<(ObjRef, Vec<Diagnostic>), Vec<Diagnostic>> {
+    compile_with_options(source, heap, loader, CompileOptions::default())
+}
+
+/// `compile_with_imports` with the choices in `options` rather than the defaults
+pub fn compile_with_options(
+    source: &str,
+    heap: &mut Heap,
+    loader: &mut ModuleLoader,
+    options: CompileOptions,
+) -> Result<(ObjRef, Vec<Diagnostic>), Vec<Diagnostic>> {
+    let file = loader.script_path().map(Path::to_path_buf);
+    if let Some(file) = &file {
+        // Lets a module importing the script report the cycle
+        if let Err(cycle) = loader.begin(file.clone()) {
+            let error = Diagnostic::error("Import cycle.", 0).in_file(file);
+            return Err(vec![cycle.into_iter().fold(error, Diagnostic::with_note)]);
+        }
+    }
+    let result = compile_file(source, file.clone(), heap, loader, Vec::new(), options);
+    if file.is_some() {
+        loader.end();
+    }
+    result
+}
+
+fn compile_file(
+    source: &str,
+    file: Option<PathBuf>,
+    heap: &mut Heap,
+    loader: &mut ModuleLoader,
+    outer_roots: Vec<Value>,
+    options: CompileOptions,
+) -> Result<(ObjRef, Vec<Diagnostic>), Vec<Diagnostic>> {
+    let mut compiler = Compiler::new(source, heap, loader, file);
+    compiler.outer_roots = outer_roots;
+    compiler.options = options;
+
+    compiler.program();
+    if compiler.diagnostics.iter().any(Diagnostic::is_error) {
+        return Err(compiler.diagnostics);
+    }
+    let (script, _) = compiler.end_function();
+    Ok((script, compiler.diagnostics))
+}
+
+impl<'a> Compiler<'a> {
+    fn new(
+        source: &'a str,
+        heap: &'a mut Heap,
+        loader: &'a mut ModuleLoader,
+        file: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            source,
+            scanner: Scanner::new(source),
+            current: Token::new("", TokenType::EOF, 0, 0, 0),
+            previous: Token::new("", TokenType::EOF, 0, 0, 0),
+            open_braces: 0,
+            heap,
+            function: FunctionState::new(FunctionKind::Script, ""),
+            enclosing: Vec::new(),
+            globals: HashMap::new(),
+            diagnostics: Vec::new(),
+            loader,
+            file,
+            outer_roots: Vec::new(),
+            pattern_literals: Vec::new(),
+            options: CompileOptions::default(),
+        }
+    }
+
+    /// Compile every declaration, collecting the errors in `diagnostics`
+    fn program(&mut self) {
+        if let Err(error) = self.advance() {
+            self.diagnostics.push(error);
+        }
+        while !self.check(TokenType::EOF) {
+            self.synchronized_declaration();
+        }
+    }
+
+    /// Move to the next token. A scanner error is returned once the characters it is
+    /// about have been skipped, so the current token is always a real one.
+    fn advance(&mut self) -> Result<(), Diagnostic> {
+        match self.current.typee {
+            TokenType::LeftBrace => self.open_braces += 1,
+            TokenType::RightBrace => self.open_braces = self.open_braces.saturating_sub(1),
+            _ => {}
+        }
+        self.previous = self.current;
+        let mut error = None;
+        loop {
+            match self.scanner.get_token() {
+                Ok(token) => {
+                    self.current = token;
+                    break;
                 }
-                println!("{:?} '{}'", token.typee, token.lexeme);
+                // Errors right after the first are usually caused by it
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        match error {
+            Some(error) => Err(self.in_file(error)),
+            None => Ok(()),
+        }
+    }
+
+    fn consume(&mut self, typee: TokenType, message: &str) -> Result<(), Diagnostic> {
+        if self.current.typee == typee {
+            return self.advance();
+        }
+        Err(self.error_at_current(message))
+    }
+
+    fn check(&self, typee: TokenType) -> bool {
+        self.current.typee == typee
+    }
+
+    /// Consume the current token if it has the given type
+    fn match_token(&mut self, typee: TokenType) -> Result<bool, Diagnostic> {
+        if !self.check(typee) {
+            return Ok(false);
+        }
+        self.advance()?;
+        Ok(true)
+    }
+
+    fn error_at(&self, token: &Token, message: &str) -> Diagnostic {
+        self.locate(Diagnostic::error(message, token.line), token)
+    }
+
+    fn error(&self, message: &str) -> Diagnostic {
+        self.error_at(&self.previous, message)
+    }
+
+    fn error_at_current(&self, message: &str) -> Diagnostic {
+        self.error_at(&self.current, message)
+    }
+
+    fn warning_at(&mut self, token: &Token, message: &str) {
+        let warning = self.locate(Diagnostic::warning(message, token.line), token);
+        self.diagnostics.push(warning);
+    }
+
+    /// Point the diagnostic at the token
+    fn locate(&self, diagnostic: Diagnostic, token: &Token) -> Diagnostic {
+        let span = match token.typee {
+            // Just past the last character, rather than on a line of trailing whitespace
+            TokenType::EOF => {
+                let end = self.source.trim_end().len();
+                Span::new(end, end)
+            }
+            _ => {
+                let span = Span::new(token.offset, token.offset + token.length);
+                return self.in_file(diagnostic.at(span, token.line, token.column));
+            }
+        };
+        self.in_file(diagnostic.with_span(self.source, span))
+    }
+
+    fn in_file(&self, diagnostic: Diagnostic) -> Diagnostic {
+        match &self.file {
+            Some(file) => diagnostic.in_file(file),
+            None => diagnostic,
+        }
+    }
+
+    fn emit_chunk(&mut self, chunk: Chunk) {
+        self.emit_chunk_at(chunk, self.previous);
+    }
+
+    /// Emit a chunk attributed to `token` rather than the last token consumed
+    fn emit_chunk_at(&mut self, chunk: Chunk, token: Token) {
+        let span = SourceSpan {
+            offset: token.offset as u32,
+            length: token.length as u32,
+            column: token.column as u32,
+        };
+        self.function
+            .bytecode
+            .push_spanned_chunk(chunk, token.line, span);
+    }
+
+    fn emit_opcode(&mut self, opcode: OpCode) {
+        self.emit_opcode_at(opcode, self.previous);
+    }
+
+    fn emit_opcode_at(&mut self, opcode: OpCode, token: Token) {
+        let offset = self.function.bytecode.chunk_count();
+        self.function.instructions.push(offset);
+        self.emit_chunk_at(opcode.into(), token);
+        if opcode == OpCode::Add {
+            self.fuse_instructions();
+        }
+    }
+
+    /// Replace the last instructions with a superinstruction when they form a sequence
+    /// that has one. Called once the last instruction is complete.
+    fn fuse_instructions(&mut self) {
+        if !self.options.superinstructions {
+            return;
+        }
+        let chunks = self.function.bytecode.get_chunks();
+
+        if let Some([get, add, set]) = self.last_instructions() {
+            if self.opcode_at(get) == Some(OpCode::GetLocal)
+                && self.opcode_at(add) == Some(OpCode::AddConstant)
+                && self.opcode_at(set) == Some(OpCode::SetLocal)
+                && chunks[get + 1] == chunks[set + 1]
+            {
+                let operands = [&chunks[add + 1..add + 4], &[chunks[set + 1]]].concat();
+                return self.replace_instructions(get, OpCode::IncrementLocal, &operands, add);
+            }
+        }
+
+        let [first, last] = match self.last_instructions() {
+            Some(instructions) => instructions,
+            None => return,
+        };
+        let jump = || chunks[last + 1..last + 3].to_vec();
+        let (opcode, operands, source) = match (self.opcode_at(first), self.opcode_at(last)) {
+            (Some(OpCode::Constant), Some(OpCode::Add)) => {
+                (OpCode::AddConstant, vec![0, 0, chunks[first + 1]], last)
+            }
+            (Some(OpCode::ConstantLong), Some(OpCode::Add)) => (
+                OpCode::AddConstant,
+                chunks[first + 1..first + 4].to_vec(),
+                last,
+            ),
+            // Runtime errors are about the comparison, so the jump takes its source
+            (Some(OpCode::Less), Some(OpCode::JumpIfFalse)) => {
+                (OpCode::LessJumpIfFalse, jump(), first)
+            }
+            (Some(OpCode::Greater), Some(OpCode::JumpIfFalse)) => {
+                (OpCode::GreaterJumpIfFalse, jump(), first)
+            }
+            (Some(OpCode::Equal), Some(OpCode::JumpIfFalse)) => {
+                (OpCode::EqualJumpIfFalse, jump(), first)
+            }
+            _ => return,
+        };
+        self.replace_instructions(first, opcode, &operands, source);
+    }
+
+    /// Offsets of the last `N` instructions, unless a jump lands between them and so
+    /// would land inside a superinstruction replacing them
+    fn last_instructions<const N: usize>(&self) -> Option<[usize; N]> {
+        let instructions = &self.function.instructions;
+        let last = instructions.get(instructions.len().checked_sub(N)?..)?;
+        let last: [usize; N] = last.try_into().ok()?;
+        match self.function.jump_target <= last[0] {
+            true => Some(last),
+            false => None,
+        }
+    }
+
+    fn opcode_at(&self, offset: usize) -> Option<OpCode> {
+        let chunk = self.function.bytecode.get_chunk(offset)?;
+        OpCode::try_from(*chunk).ok()
+    }
+
+    /// Replace the instructions from `start` on with a single one, attributed to the
+    /// source of the instruction at `source`
+    fn replace_instructions(
+        &mut self,
+        start: usize,
+        opcode: OpCode,
+        operands: &[Chunk],
+        source: usize,
+    ) {
+        let bytecode = &mut self.function.bytecode;
+        let line = bytecode.get_line(source).copied().unwrap_or_default();
+        let span = bytecode.get_span(source);
+        bytecode.truncate(start);
+        let instructions = &mut self.function.instructions;
+        while instructions.last().is_some_and(|offset| *offset >= start) {
+            instructions.pop();
+        }
+        instructions.push(start);
+        for chunk in std::iter::once(opcode.into()).chain(operands.iter().copied()) {
+            match span {
+                Some(span) => bytecode.push_spanned_chunk(chunk, line, span),
+                None => bytecode.push_chunk(chunk, line),
+            }
+        }
+    }
+
+    /// Offset of the next instruction, which a jump will land on
+    fn jump_target(&mut self) -> usize {
+        let offset = self.function.bytecode.chunk_count();
+        self.function.jump_target = offset;
+        offset
+    }
+
+    /// Emit a two chunk operand
+    fn emit_short(&mut self, operand: usize) {
+        self.emit_chunk((operand >> 8) as Chunk);
+        self.emit_chunk(operand as Chunk);
+    }
+
+    /// Emit a three chunk operand
+    fn emit_long(&mut self, operand: usize) {
+        self.emit_chunk((operand >> 16) as Chunk);
+        self.emit_short(operand & 0xffff);
+    }
+
+    fn emit_constant(&mut self, value: Value) -> Result<(), Diagnostic> {
+        let index = self.make_constant(value)?;
+        match Chunk::try_from(index) {
+            Ok(index) => self.emit_operand_instruction(OpCode::Constant, index),
+            Err(_) => {
+                self.emit_opcode(OpCode::ConstantLong);
+                self.emit_long(index);
+            }
+        }
+        Ok(())
+    }
+
+    fn emit_operand_instruction(&mut self, opcode: OpCode, operand: Chunk) {
+        self.emit_opcode(opcode);
+        self.emit_chunk(operand);
+    }
+
+    /// Emit an instruction whose operand is a constant index
+    fn emit_constant_instruction(&mut self, opcode: OpCode, index: usize) {
+        self.emit_opcode(opcode);
+        self.emit_long(index);
+    }
+
+    /// Emit an instruction reading or writing a local, upvalue or global, whose
+    /// operand is a slot, an upvalue index or a name constant respectively
+    fn emit_variable_instruction(&mut self, opcode: OpCode, operand: usize) {
+        match opcode {
+            OpCode::GetGlobal | OpCode::SetGlobal => {
+                self.emit_constant_instruction(opcode, operand)
+            }
+            // Slots and upvalue indices are bounded by MAX_LOCALS and MAX_UPVALUES
+            _ => self.emit_operand_instruction(opcode, operand as Chunk),
+        }
+        if opcode == OpCode::SetLocal {
+            self.fuse_instructions();
+        }
+    }
+
+    fn make_constant(&mut self, value: Value) -> Result<usize, Diagnostic> {
+        let index = self.function.bytecode.push_constant(value);
+        if index > MAX_LONG {
+            return Err(self.error("Too many constants in one function."));
+        }
+        Ok(index)
+    }
+
+    fn identifier_constant(&mut self, name: &str) -> Result<usize, Diagnostic> {
+        let name = self.intern(name);
+        self.make_constant(Value::Object(name))
+    }
+
+    /// Emit a jump with a placeholder operand and return the operand's offset for patching
+    fn emit_jump(&mut self, opcode: OpCode) -> usize {
+        self.emit_opcode(opcode);
+        self.emit_short(MAX_SHORT);
+        self.fuse_instructions();
+        self.function.bytecode.chunk_count() - 2
+    }
+
+    /// Point a previously emitted jump at the next instruction to be emitted
+    fn patch_jump(&mut self, offset: usize) -> Result<(), Diagnostic> {
+        let jump = self.jump_target() - offset - 2;
+        if jump > MAX_SHORT {
+            return Err(self.error("Too much code to jump over."));
+        }
+        self.function.bytecode.set_short(offset, jump);
+        Ok(())
+    }
+
+    /// Emit a backward jump to `loop_start`
+    fn emit_loop(&mut self, loop_start: usize) -> Result<(), Diagnostic> {
+        self.emit_opcode(OpCode::Loop);
+        // Also skip over the operand itself
+        let jump = self.function.bytecode.chunk_count() - loop_start + 2;
+        if jump > MAX_SHORT {
+            return Err(self.error("Loop body too large."));
+        }
+        self.emit_short(jump);
+        Ok(())
+    }
+
+    fn emit_return(&mut self) {
+        self.emit_opcode(OpCode::Nil);
+        self.emit_opcode(OpCode::Return);
+    }
+
+    /// Finish the innermost function, allocate it and resume compiling its enclosing one.
+    /// Also returns the variables the function captures.
+    fn end_function(&mut self) -> (ObjRef, Vec<Upvalue>) {
+        self.emit_return();
+
+        // Named while the function's constants are still roots
+        let name = match self.function.kind {
+            FunctionKind::Script => None,
+            FunctionKind::Function | FunctionKind::Method => Some(self.intern(self.function.name)),
+        };
+        let finished = match self.enclosing.pop() {
+            Some(enclosing) => std::mem::replace(&mut self.function, enclosing),
+            None => std::mem::replace(
+                &mut self.function,
+                FunctionState::new(FunctionKind::Script, ""),
+            ),
+        };
+
+        #[cfg(feature = "debug_print_code")]
+        disassembler(
+            &finished.bytecode,
+            self.heap,
+            name.map_or("<script>", |_| finished.name),
+        );
+
+        let function = self.alloc(Object::Function(ObjFunction {
+            name,
+            arity: finished.arity,
+            upvalue_count: finished.upvalues.len(),
+            bytecode: std::rc::Rc::new(finished.bytecode),
+        }));
+        (function, finished.upvalues)
+    }
+
+    fn begin_scope(&mut self) {
+        self.function.scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.function.scope_depth -= 1;
+
+        while let Some(local) = self.function.locals.last() {
+            match local.depth {
+                Some(depth) if depth <= self.function.scope_depth => break,
+                _ => {
+                    self.emit_opcode(discard_opcode(local));
+                    self.function.locals.pop();
+                }
+            }
+        }
+    }
+
+    /// Every object allocated so far that compiling can still use: the constants of
+    /// the functions being compiled and of the files importing this one
+    fn roots(&self) -> Vec<Value> {
+        let constants = std::iter::once(&self.function)
+            .chain(&self.enclosing)
+            .flat_map(|state| state.bytecode.get_constants().iter().copied());
+        self.outer_roots
+            .iter()
+            .chain(&self.pattern_literals)
+            .copied()
+            .chain(constants)
+            .collect()
+    }
+
+    /// Allocate an object, first collecting garbage if enough has been allocated.
+    /// Objects the compiler holds outside the roots must be reachable from `object`.
+    fn alloc(&mut self, object: Object) -> ObjRef {
+        if self.heap.should_collect() {
+            let mut roots = self.roots();
+            object.trace(|value| roots.push(value));
+            self.heap.collect(roots);
+        }
+        self.heap.alloc(object)
+    }
+
+    /// Return the interned string with these contents, first collecting garbage if
+    /// enough has been allocated
+    fn intern(&mut self, chars: &str) -> ObjRef {
+        if self.heap.should_collect() {
+            let roots = self.roots();
+            self.heap.collect(roots);
+        }
+        self.heap.intern(chars)
+    }
+
+    /// Compile a declaration. After an error the compiler is in panic mode: the error
+    /// is recorded and tokens are skipped up to the next statement, where compiling
+    /// resumes, so the errors that follow from the first aren't reported.
+    fn synchronized_declaration(&mut self) {
+        let checkpoint = self.checkpoint();
+        let start = self.current.offset;
+        if let Err(error) = self.declaration() {
+            self.diagnostics.push(error);
+            let open_braces = checkpoint.open_braces;
+            self.restore(checkpoint);
+            if self.current.offset == start {
+                // Nothing was consumed, skip the token the error is about
+                let _ = self.advance();
+            }
+            self.synchronize(open_braces);
+        }
+    }
+
+    /// Skip tokens up to one that starts a statement or closes the enclosing block.
+    /// Braces the abandoned declaration opened are skipped up to their `}`, and at the
+    /// top level a `}` closes nothing and is skipped too.
+    fn synchronize(&mut self, open_braces: usize) {
+        let in_block = self.function.scope_depth > 0 || !self.enclosing.is_empty();
+        loop {
+            match self.current.typee {
+                TokenType::EOF => return,
+                _ if self.open_braces > open_braces => {
+                    let _ = self.advance();
+                }
+                TokenType::RightBrace if in_block => return,
+                TokenType::Let
+                | TokenType::Function
+                | TokenType::Struct
+                | TokenType::If
+                | TokenType::While
+                | TokenType::For
+                | TokenType::Return => return,
+                _ => {
+                    // Skipped code doesn't get its own errors
+                    let _ = self.advance();
+                }
+            }
+        }
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            functions: self.enclosing.len(),
+            scope_depth: self.function.scope_depth,
+            locals: self.function.locals.len(),
+            loops: self.function.loops.len(),
+            temporaries: self.function.temporaries,
+            open_braces: self.open_braces,
+        }
+    }
+
+    /// Abandon the functions, scopes and loops entered since the checkpoint
+    fn restore(&mut self, checkpoint: Checkpoint) {
+        while self.enclosing.len() > checkpoint.functions {
+            if let Some(enclosing) = self.enclosing.pop() {
+                self.function = enclosing;
+            }
+        }
+        self.function.scope_depth = checkpoint.scope_depth;
+        self.function.locals.truncate(checkpoint.locals);
+        self.function.loops.truncate(checkpoint.loops);
+        self.function.temporaries = checkpoint.temporaries;
+    }
+
+    fn declaration(&mut self) -> Result<(), Diagnostic> {
+        if self.match_token(TokenType::Let)? {
+            self.let_declaration()?;
+        } else if self.check(TokenType::Function) && self.check_next_identifier() {
+            self.advance()?;
+            self.function_declaration()?;
+        } else if self.match_token(TokenType::Struct)? {
+            self.struct_declaration()?;
+        } else if self.match_token(TokenType::Public)? {
+            self.public_declaration()?;
+        } else if self.match_token(TokenType::Import)? {
+            self.import_declaration()?;
+        } else {
+            self.statement()?;
+        }
+        Ok(())
+    }
+
+    /// A top-level declaration that modules importing this one can see
+    fn public_declaration(&mut self) -> Result<(), Diagnostic> {
+        if self.function.kind != FunctionKind::Script || self.function.scope_depth > 0 {
+            return Err(self.error("Only top-level declarations can be public."));
+        }
+
+        let global = if self.match_token(TokenType::Let)? {
+            self.let_declaration()?
+        } else if self.match_token(TokenType::Function)? {
+            self.function_declaration()?
+        } else if self.match_token(TokenType::Struct)? {
+            self.struct_declaration()?
+        } else {
+            return Err(
+                self.error_at_current("Expect 'let', 'function' or 'struct' after 'public'.")
+            );
+        };
+        self.emit_constant_instruction(OpCode::Export, global);
+        Ok(())
+    }
+
+    /// `import "path/to/module"` binds the module to its file name, while
+    /// `import first, second from "path/to/module"` binds its public members
+    fn import_declaration(&mut self) -> Result<(), Diagnostic> {
+        let mut names = Vec::new();
+        if self.check(TokenType::Identifier) {
+            loop {
+                self.consume(TokenType::Identifier, "Expect name to import.")?;
+                names.push(self.previous);
+                if !self.match_token(TokenType::Comma)? {
+                    break;
+                }
+            }
+            self.consume(TokenType::From, "Expect 'from' after imported names.")?;
+        }
+        self.consume(TokenType::String, "Expect module path string.")?;
+        let path = self.previous;
+        let module = self.load_module(&path)?;
+        let module = self.make_constant(Value::Object(module))?;
+
+        if names.is_empty() {
+            let name = module_name(path.lexeme);
+            if !is_identifier(name) {
+                return Err(self.error(&format!(
+                    "Module name '{}' is not a valid identifier, import names from it instead.",
+                    name
+                )));
+            }
+            let global = self.declare_variable(name, false)?;
+            self.emit_constant_instruction(OpCode::Import, module);
+            self.emit_opcode(OpCode::Pop);
+            self.define_variable(global, false);
+        }
+        for name in names {
+            let global = self.declare_variable(name.lexeme, false)?;
+            self.emit_constant_instruction(OpCode::Import, module);
+            self.emit_opcode(OpCode::Pop);
+            let member = self.identifier_constant(name.lexeme)?;
+            self.emit_constant_instruction(OpCode::GetProperty, member);
+            self.define_variable(global, false);
+        }
+        Ok(())
+    }
+
+    /// Compile the file a path string token refers to, unless that was already done,
+    /// and return its module
+    fn load_module(&mut self, token: &Token) -> Result<ObjRef, Diagnostic> {
+        let import = unescape(&token.lexeme[1..token.lexeme.len() - 1]);
+        let path = match self.loader.resolve(self.file.as_deref(), &import) {
+            Ok(path) => path,
+            Err(message) => return Err(self.error(&message)),
+        };
+        if let Some(module) = self.loader.get(&path) {
+            return Ok(module);
+        }
+
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(e) => {
+                return Err(self.error(&format!("Could not read module '{}': {}.", import, e)))
+            }
+        };
+        if let Err(cycle) = self.loader.begin(path.clone()) {
+            let error = self.error(&format!("Importing '{}' creates a cycle.", import));
+            return Err(cycle.into_iter().fold(error, Diagnostic::with_note));
+        }
+        // The name is a root while the module compiles, and the script only lives in
+        // this function until the module refers to it
+        let name = self.intern(module_name(token.lexeme));
+        let mut roots = self.roots();
+        roots.push(Value::Object(name));
+        let result = compile_file(
+            &source,
+            Some(path.clone()),
+            self.heap,
+            self.loader,
+            roots,
+            self.options,
+        );
+        self.loader.end();
+        let script = match result {
+            Ok((script, warnings)) => {
+                self.diagnostics.extend(warnings);
+                script
+            }
+            Err(diagnostics) => {
+                let note = format!("In module '{}' imported at {}.", import, self.site(token));
+                let mut diagnostics: Vec<Diagnostic> = diagnostics
+                    .into_iter()
+                    .map(|diagnostic| diagnostic.with_note(note.clone()))
+                    .collect();
+                // The module's last error stands for the import failing
+                let error = match diagnostics.iter().rposition(Diagnostic::is_error) {
+                    Some(last) => diagnostics.remove(last),
+                    None => self.error(&format!("Could not compile module '{}'.", import)),
+                };
+                self.diagnostics.extend(diagnostics);
+                return Err(error);
+            }
+        };
+
+        let module = self.alloc(Object::Module(ObjModule::new(
+            name,
+            Some(script),
+            Some(path.clone()),
+        )));
+        // Modules stay cached for the rest of the program
+        self.heap.add_permanent_root(module);
+        self.loader.insert(path, module);
+        Ok(module)
+    }
+
+    /// Where the token is, as `file:line:column` with the file relative to the script
+    fn site(&self, token: &Token) -> String {
+        let file = match &self.file {
+            Some(file) => self.loader.display_path(file),
+            None => "line".to_string(),
+        };
+        format!("{}:{}:{}", file, token.line, token.column)
+    }
+
+    /// Whether the token after the current one is an identifier, which tells a
+    /// function declaration apart from an anonymous function expression
+    fn check_next_identifier(&self) -> bool {
+        let mut lookahead = self.scanner.clone();
+        matches!(lookahead.get_token(), Ok(token) if token.typee == TokenType::Identifier)
+    }
+
+    fn function_declaration(&mut self) -> Result<usize, Diagnostic> {
+        let global = self.parse_variable("Expect function name.", false)?;
+        // A function can refer to itself for recursion before its body is finished
+        if self.function.scope_depth > 0 {
+            self.mark_initialized();
+        }
+        self.function(FunctionKind::Function, self.previous.lexeme)?;
+        self.define_variable(global, false);
+        Ok(global)
+    }
+
+    /// `struct Name { public field other_field public function method() { ... } }`
+    fn struct_declaration(&mut self) -> Result<usize, Diagnostic> {
+        let global = self.parse_variable("Expect struct name.", false)?;
+        let name = self.previous;
+        let name_constant = self.identifier_constant(name.lexeme)?;
+        self.emit_constant_instruction(OpCode::Struct, name_constant);
+        self.define_variable(global, false);
+
+        // Load the struct back so members can be added to it
+        self.named_variable(name, false)?;
+        self.consume(TokenType::LeftBrace, "Expect '{' before struct body.")?;
+        let mut members = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            let public = self.match_token(TokenType::Public)?;
+            let is_method = self.match_token(TokenType::Function)?;
+            self.consume(TokenType::Identifier, "Expect field or method name.")?;
+            let member = self.previous.lexeme;
+            if members.contains(&member) {
+                return Err(self.error(&format!(
+                    "Already a member named '{}' in this struct.",
+                    member
+                )));
+            }
+            members.push(member);
+
+            let constant = self.identifier_constant(member)?;
+            if is_method {
+                self.function(FunctionKind::Method, member)?;
+                self.emit_constant_instruction(OpCode::Method, constant);
+            } else {
+                self.emit_constant_instruction(OpCode::Field, constant);
+            }
+            self.emit_chunk(public.into());
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after struct body.")?;
+        self.emit_opcode(OpCode::Pop);
+        Ok(global)
+    }
+
+    /// Compile a parameter list and body into a new function and push it
+    fn function(&mut self, kind: FunctionKind, name: &'a str) -> Result<(), Diagnostic> {
+        let enclosing = std::mem::replace(&mut self.function, FunctionState::new(kind, name));
+        self.enclosing.push(enclosing);
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.")?;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.function.arity += 1;
+                if self.function.arity > MAX_ARITY {
+                    return Err(self.error_at_current("Can't have more than 255 parameters."));
+                }
+                let parameter = self.parse_variable("Expect parameter name.", false)?;
+                self.define_variable(parameter, false);
+                if !self.match_token(TokenType::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.")?;
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.")?;
+        self.block()?;
+
+        let (function, upvalues) = self.end_function();
+        let index = self.make_constant(Value::Object(function))?;
+        self.emit_constant_instruction(OpCode::Closure, index);
+        for upvalue in upvalues {
+            self.emit_chunk(upvalue.is_local.into());
+            self.emit_chunk(upvalue.index as Chunk);
+        }
+        Ok(())
+    }
+
+    fn let_declaration(&mut self) -> Result<usize, Diagnostic> {
+        let mutable = self.match_token(TokenType::Mutable)?;
+        let global = self.parse_variable("Expect variable name.", mutable)?;
+        let name = self.previous;
+
+        if self.match_token(TokenType::Equal)? {
+            self.expression()?;
+        } else if mutable {
+            self.emit_opcode(OpCode::Nil);
+        } else {
+            return Err(self.error_at(&name, "Immutable variable must be initialized."));
+        }
+
+        self.define_variable(global, mutable);
+        Ok(global)
+    }
+
+    /// Consume a variable name and declare it
+    fn parse_variable(&mut self, message: &str, mutable: bool) -> Result<usize, Diagnostic> {
+        self.consume(TokenType::Identifier, message)?;
+        self.declare_variable(self.previous.lexeme, mutable)
+    }
+
+    /// Declare a variable in the current scope. Returns the constant index of the name
+    /// for globals, locals don't need one.
+    fn declare_variable(&mut self, name: &'a str, mutable: bool) -> Result<usize, Diagnostic> {
+        if self.function.scope_depth > 0 {
+            self.add_local(name, mutable)?;
+            return Ok(0);
+        }
+
+        self.globals.insert(name, mutable);
+        self.identifier_constant(name)
+    }
 
-                if token.typee == TokenType::EOF {
-                    return Ok(());
+    fn add_local(&mut self, name: &'a str, mutable: bool) -> Result<(), Diagnostic> {
+        for local in self.function.locals.iter().rev() {
+            if let Some(depth) = local.depth {
+                if depth < self.function.scope_depth {
+                    break;
                 }
             }
-            Err(token) => {
-                eprintln!("{}", token.lexeme);
-                return Err(InterpretError::CompileError);
+            if local.name == name {
+                return Err(self.error("Already a variable with this name in this scope."));
             }
         }
+
+        let slot = self.next_slot();
+        if slot >= MAX_LOCALS {
+            return Err(self.error("Too many local variables in scope."));
+        }
+        self.function.locals.push(Local {
+            name,
+            slot,
+            depth: None,
+            mutable,
+            captured: false,
+        });
+        Ok(())
+    }
+
+    /// Stack slot the next value pushed by the current function will occupy
+    fn next_slot(&self) -> usize {
+        let base = match self.function.locals.last() {
+            // A local whose initializer is being compiled doesn't have its value yet
+            Some(Local {
+                slot, depth: None, ..
+            }) => *slot,
+            Some(local) => local.slot + 1,
+            None => 0,
+        };
+        base + self.function.temporaries
+    }
+
+    /// Compile an operand while `count` values of the enclosing expression are still
+    /// on the stack, so locals declared inside it get the right slots
+    fn with_temporaries(
+        &mut self,
+        count: usize,
+        operand: impl FnOnce(&mut Self) -> Result<(), Diagnostic>,
+    ) -> Result<(), Diagnostic> {
+        // Restored rather than decremented, an error in the operand can leave any count
+        let temporaries = self.function.temporaries;
+        self.function.temporaries += count;
+        let result = operand(self);
+        self.function.temporaries = temporaries;
+        result
+    }
+
+    fn mark_initialized(&mut self) {
+        if let Some(local) = self.function.locals.last_mut() {
+            local.depth = Some(self.function.scope_depth);
+        }
+    }
+
+    fn define_variable(&mut self, global: usize, mutable: bool) {
+        if self.function.scope_depth > 0 {
+            // The value is already in the local's stack slot
+            self.mark_initialized();
+            return;
+        }
+
+        let opcode = match mutable {
+            true => OpCode::DefineMutableGlobal,
+            false => OpCode::DefineGlobal,
+        };
+        self.emit_constant_instruction(opcode, global);
+    }
+
+    /// The function at `level` of the chain being compiled, where the outermost is
+    /// level zero and the innermost is `self.function`
+    fn function_at(&mut self, level: usize) -> &mut FunctionState<'a> {
+        match self.enclosing.get_mut(level) {
+            Some(state) => state,
+            None => &mut self.function,
+        }
+    }
+
+    /// Look `name` up among the locals of the function at `level`
+    fn resolve_local(
+        &mut self,
+        level: usize,
+        name: &Token,
+    ) -> Result<Option<(usize, bool)>, Diagnostic> {
+        let found = self
+            .function_at(level)
+            .locals
+            .iter()
+            .rev()
+            .find(|local| local.name == name.lexeme);
+
+        match found {
+            Some(Local { depth: None, .. }) => {
+                Err(self.error_at(name, "Can't read local variable in its own initializer."))
+            }
+            Some(local) => Ok(Some((local.slot, local.mutable))),
+            None => Ok(None),
+        }
+    }
+
+    /// Flag the local in `slot` of the function at `level` as referenced by a closure
+    fn mark_captured(&mut self, level: usize, slot: usize) {
+        let locals = &mut self.function_at(level).locals;
+        if let Some(local) = locals.iter_mut().rev().find(|local| local.slot == slot) {
+            local.captured = true;
+        }
+    }
+
+    /// Look `name` up among the locals of the functions enclosing the one at `level`,
+    /// capturing it into every function in between
+    fn resolve_upvalue(
+        &mut self,
+        level: usize,
+        name: &Token,
+    ) -> Result<Option<(usize, bool)>, Diagnostic> {
+        if level == 0 {
+            return Ok(None);
+        }
+
+        if let Some((slot, mutable)) = self.resolve_local(level - 1, name)? {
+            self.mark_captured(level - 1, slot);
+            return self.add_upvalue(level, slot, true, mutable).map(Some);
+        }
+        match self.resolve_upvalue(level - 1, name)? {
+            Some((index, mutable)) => self.add_upvalue(level, index, false, mutable).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the index of the upvalue in the function at `level`, reusing an existing
+    /// one when the variable was already captured
+    fn add_upvalue(
+        &mut self,
+        level: usize,
+        index: usize,
+        is_local: bool,
+        mutable: bool,
+    ) -> Result<(usize, bool), Diagnostic> {
+        let upvalues = &self.function_at(level).upvalues;
+        if let Some(existing) = upvalues
+            .iter()
+            .position(|upvalue| upvalue.index == index && upvalue.is_local == is_local)
+        {
+            return Ok((existing, upvalues[existing].mutable));
+        }
+        if upvalues.len() == MAX_UPVALUES {
+            return Err(self.error("Too many closure variables in function."));
+        }
+
+        let upvalues = &mut self.function_at(level).upvalues;
+        upvalues.push(Upvalue {
+            index,
+            is_local,
+            mutable,
+        });
+        Ok((upvalues.len() - 1, mutable))
+    }
+
+    fn statement(&mut self) -> Result<(), Diagnostic> {
+        if self.match_token(TokenType::If)? {
+            self.if_statement()
+        } else if self.match_token(TokenType::While)? {
+            self.while_statement()
+        } else if self.match_token(TokenType::For)? {
+            self.for_statement()
+        } else if self.match_token(TokenType::Break)? {
+            self.break_statement()
+        } else if self.match_token(TokenType::Continue)? {
+            self.continue_statement()
+        } else if self.match_token(TokenType::Return)? {
+            self.return_statement()
+        } else if self.match_token(TokenType::LeftBrace)? {
+            self.scoped_block()
+        } else {
+            self.expression_statement()
+        }
+    }
+
+    /// Compile a block that has already had its `{` consumed in a new scope
+    fn scoped_block(&mut self) -> Result<(), Diagnostic> {
+        self.begin_scope();
+        self.block()?;
+        self.end_scope();
+        Ok(())
+    }
+
+    /// A block that must follow a control flow header such as `if condition`
+    fn body(&mut self, message: &str) -> Result<(), Diagnostic> {
+        self.consume(TokenType::LeftBrace, message)?;
+        self.scoped_block()
+    }
+
+    fn if_statement(&mut self) -> Result<(), Diagnostic> {
+        self.expression()?;
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_opcode(OpCode::Pop);
+        self.body("Expect '{' after if condition.")?;
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump)?;
+        self.emit_opcode(OpCode::Pop);
+
+        if self.match_token(TokenType::ElseIf)? {
+            self.if_statement()?;
+        } else if self.match_token(TokenType::Else)? {
+            if self.match_token(TokenType::If)? {
+                self.if_statement()?;
+            } else {
+                self.body("Expect '{' after else.")?;
+            }
+        }
+        self.patch_jump(else_jump)
+    }
+
+    fn while_statement(&mut self) -> Result<(), Diagnostic> {
+        let loop_start = self.jump_target();
+        self.expression()?;
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_opcode(OpCode::Pop);
+        self.loop_body(
+            loop_start,
+            self.function.scope_depth,
+            "Expect '{' after while condition.",
+        )?;
+        self.emit_loop(loop_start)?;
+
+        self.patch_jump(exit_jump)?;
+        self.emit_opcode(OpCode::Pop);
+        self.patch_breaks()
+    }
+
+    /// `for item in iterable { ... }`. The iterator lives in a hidden local for the
+    /// duration of the loop and each item gets a fresh local in the body's scope.
+    fn for_statement(&mut self) -> Result<(), Diagnostic> {
+        self.begin_scope();
+
+        self.consume(
+            TokenType::Identifier,
+            "Expect loop variable name after 'for'.",
+        )?;
+        let name = self.previous.lexeme;
+        self.consume(TokenType::In, "Expect 'in' after loop variable.")?;
+
+        self.expression()?;
+        self.emit_opcode(OpCode::GetIter);
+        // The name can't clash with a user variable since identifiers aren't empty
+        self.add_local("", false)?;
+        self.mark_initialized();
+
+        let iterator_depth = self.function.scope_depth;
+        let loop_start = self.jump_target();
+        let exit_jump = self.emit_jump(OpCode::ForIter);
+
+        self.begin_scope();
+        self.add_local(name, false)?;
+        self.mark_initialized();
+        self.loop_body(loop_start, iterator_depth, "Expect '{' after for iterable.")?;
+        self.end_scope();
+        self.emit_loop(loop_start)?;
+
+        self.patch_jump(exit_jump)?;
+        self.patch_breaks()?;
+        self.end_scope();
+        Ok(())
+    }
+
+    /// `scope_depth` is the depth of the scope enclosing the loop's own locals
+    fn loop_body(
+        &mut self,
+        start: usize,
+        scope_depth: usize,
+        message: &str,
+    ) -> Result<(), Diagnostic> {
+        self.function.loops.push(Loop {
+            start,
+            scope_depth,
+            breaks: Vec::new(),
+        });
+        self.body(message)
+    }
+
+    /// Point every `break` in the loop that just ended at the next instruction
+    fn patch_breaks(&mut self) -> Result<(), Diagnostic> {
+        if let Some(finished) = self.function.loops.pop() {
+            for jump in finished.breaks {
+                self.patch_jump(jump)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Pop the locals of the scopes deeper than `scope_depth` without forgetting them,
+    /// for code that jumps out of those scopes
+    fn discard_locals(&mut self, scope_depth: usize) {
+        let opcodes: Vec<OpCode> = self
+            .function
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_some_and(|depth| depth > scope_depth))
+            .map(discard_opcode)
+            .collect();
+        for opcode in opcodes {
+            self.emit_opcode(opcode);
+        }
+    }
+
+    fn return_statement(&mut self) -> Result<(), Diagnostic> {
+        if self.function.kind == FunctionKind::Script {
+            return Err(self.error("Can't return from top-level code."));
+        }
+
+        // Without statement terminators a bare `return` is one not followed by an expression
+        if get_rule(self.current.typee).prefix.is_none() {
+            self.emit_return();
+        } else {
+            self.expression()?;
+            self.emit_opcode(OpCode::Return);
+        }
+        Ok(())
+    }
+
+    fn break_statement(&mut self) -> Result<(), Diagnostic> {
+        let scope_depth = match self.function.loops.last() {
+            Some(innermost) => innermost.scope_depth,
+            None => return Err(self.error("Can't use 'break' outside of a loop.")),
+        };
+        self.discard_locals(scope_depth);
+        let jump = self.emit_jump(OpCode::Jump);
+        if let Some(innermost) = self.function.loops.last_mut() {
+            innermost.breaks.push(jump);
+        }
+        Ok(())
+    }
+
+    fn continue_statement(&mut self) -> Result<(), Diagnostic> {
+        let (start, scope_depth) = match self.function.loops.last() {
+            Some(innermost) => (innermost.start, innermost.scope_depth),
+            None => return Err(self.error("Can't use 'continue' outside of a loop.")),
+        };
+        self.discard_locals(scope_depth);
+        self.emit_loop(start)
+    }
+
+    fn block(&mut self) -> Result<(), Diagnostic> {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            self.synchronized_declaration();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.")
+    }
+
+    fn expression_statement(&mut self) -> Result<(), Diagnostic> {
+        self.expression()?;
+        self.emit_opcode(OpCode::Pop);
+        Ok(())
+    }
+
+    fn expression(&mut self) -> Result<(), Diagnostic> {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) -> Result<(), Diagnostic> {
+        self.advance()?;
+        let prefix = match get_rule(self.previous.typee).prefix {
+            Some(rule) => rule,
+            None => return Err(self.error("Expect expression.")),
+        };
+        let can_assign = precedence <= Precedence::Assignment;
+        prefix(self, can_assign)?;
+
+        while precedence <= get_rule(self.current.typee).precedence {
+            self.advance()?;
+            if let Some(infix) = get_rule(self.previous.typee).infix {
+                infix(self, can_assign)?;
+            }
+        }
+
+        if can_assign
+            && (self.check(TokenType::Equal) || compound_operator(self.current.typee).is_some())
+        {
+            return Err(self.error_at_current("Invalid assignment target."));
+        }
+        Ok(())
+    }
+
+    fn variable(&mut self, can_assign: bool) -> Result<(), Diagnostic> {
+        self.named_variable(self.previous, can_assign)
+    }
+
+    fn named_variable(&mut self, name: Token<'a>, can_assign: bool) -> Result<(), Diagnostic> {
+        let level = self.enclosing.len();
+        let (get_op, set_op, operand, mutable) =
+            if let Some((slot, mutable)) = self.resolve_local(level, &name)? {
+                (OpCode::GetLocal, OpCode::SetLocal, slot, Some(mutable))
+            } else if let Some((index, mutable)) = self.resolve_upvalue(level, &name)? {
+                (OpCode::GetUpvalue, OpCode::SetUpvalue, index, Some(mutable))
+            } else {
+                let operand = self.identifier_constant(name.lexeme)?;
+                // Globals declared elsewhere are checked when the assignment runs
+                let mutable = self.globals.get(name.lexeme).copied();
+                (OpCode::GetGlobal, OpCode::SetGlobal, operand, mutable)
+            };
+
+        let compound = compound_operator(self.current.typee);
+        if can_assign && (self.check(TokenType::Equal) || compound.is_some()) {
+            if mutable == Some(false) {
+                return Err(self.error_at(
+                    &name,
+                    &format!("Cannot assign to immutable variable '{}'.", name.lexeme),
+                ));
+            }
+            self.advance()?;
+
+            if let Some(operator) = compound {
+                self.emit_variable_instruction(get_op, operand);
+                self.with_temporaries(1, Self::expression)?;
+                self.emit_opcode(operator);
+            } else {
+                self.expression()?;
+            }
+            self.emit_variable_instruction(set_op, operand);
+        } else {
+            self.emit_variable_instruction(get_op, operand);
+        }
+        Ok(())
+    }
+
+    fn number(&mut self, _can_assign: bool) -> Result<(), Diagnostic> {
+        match self.previous.lexeme.parse::<f64>() {
+            Ok(value) => self.emit_constant(Value::Number(value)),
+            Err(_) => Err(self.error("Invalid number literal.")),
+        }
+    }
+
+    /// Push the contents of the previous string token without its delimiters
+    fn emit_string_fragment(&mut self) -> Result<(), Diagnostic> {
+        let lexeme = self.previous.lexeme;
+        let string = self.intern(&unescape(&lexeme[1..lexeme.len() - 1]));
+        self.emit_constant(Value::Object(string))
+    }
+
+    fn string(&mut self, _can_assign: bool) -> Result<(), Diagnostic> {
+        self.emit_string_fragment()?;
+        Ok(())
+    }
+
+    /// `"a {x} b"` is lowered to `"a " + to_string(x) + " b"`
+    fn interpolation(&mut self, _can_assign: bool) -> Result<(), Diagnostic> {
+        self.emit_string_fragment()?;
+        loop {
+            // The string built so far is on the stack
+            self.with_temporaries(1, Self::expression)?;
+            self.emit_opcode(OpCode::ToString);
+            self.emit_opcode(OpCode::Add);
+
+            match self.current.typee {
+                TokenType::InterpolationMiddle => {
+                    self.advance()?;
+                }
+                TokenType::InterpolationEnd => {
+                    self.advance()?;
+                    break;
+                }
+                _ => return Err(self.error_at_current("Expect '}' after interpolated expression.")),
+            }
+
+            // Empty fragments between and after expressions add nothing
+            if self.previous.lexeme.len() > 2 {
+                self.emit_string_fragment()?;
+                self.emit_opcode(OpCode::Add);
+            }
+        }
+
+        if self.previous.lexeme.len() > 2 {
+            self.emit_string_fragment()?;
+            self.emit_opcode(OpCode::Add);
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, _can_assign: bool) -> Result<(), Diagnostic> {
+        let paren = self.previous;
+        let count = self.argument_list()?;
+        self.emit_opcode_at(OpCode::Call, paren);
+        self.emit_chunk_at(count as Chunk, paren);
+        Ok(())
+    }
+
+    fn argument_list(&mut self) -> Result<usize, Diagnostic> {
+        let mut count = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                // The callee and the arguments before this one are on the stack
+                self.with_temporaries(1 + count, Self::expression)?;
+                count += 1;
+                if count > MAX_ARITY {
+                    return Err(self.error("Can't have more than 255 arguments."));
+                }
+                if !self.match_token(TokenType::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.")?;
+        Ok(count)
+    }
+
+    /// An anonymous function expression such as `function (x) { return x * 2 }`
+    fn function_expression(&mut self, _can_assign: bool) -> Result<(), Diagnostic> {
+        self.function(FunctionKind::Function, "anonymous")
+    }
+
+    fn dot(&mut self, can_assign: bool) -> Result<(), Diagnostic> {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.")?;
+        let name = self.identifier_constant(self.previous.lexeme)?;
+
+        if can_assign && self.match_token(TokenType::Equal)? {
+            self.with_temporaries(1, Self::expression)?;
+            self.emit_constant_instruction(OpCode::SetProperty, name);
+        } else {
+            self.emit_constant_instruction(OpCode::GetProperty, name);
+        }
+        Ok(())
+    }
+
+    /// `self` is the receiver in slot zero of the enclosing method
+    fn self_expression(&mut self, _can_assign: bool) -> Result<(), Diagnostic> {
+        let in_method = std::iter::once(&self.function)
+            .chain(&self.enclosing)
+            .any(|state| state.kind == FunctionKind::Method);
+        if !in_method {
+            return Err(self.error("Can't use 'self' outside of a method."));
+        }
+        self.named_variable(self.previous, false)
+    }
+
+    /// `match value { pattern => expression, pattern if guard => expression }` is
+    /// compiled to a chain of tests, one arm after the other. The value lives in a
+    /// hidden local whose slot ends up holding the result.
+    fn match_expression(&mut self, _can_assign: bool) -> Result<(), Diagnostic> {
+        self.expression()?;
+        self.consume(TokenType::LeftBrace, "Expect '{' after match value.")?;
+
+        self.begin_scope();
+        self.add_local("", false)?;
+        self.mark_initialized();
+        let slot = self.next_slot() - self.function.temporaries - 1;
+        // Values below the hidden local don't affect the slots of the arms' bindings
+        let temporaries = std::mem::take(&mut self.function.temporaries);
+
+        let mut end_jumps = Vec::new();
+        let mut exhaustive = false;
+        let mut literals = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            let start = self.current;
+            let pattern = self.pattern()?;
+            let guarded = self.check(TokenType::If);
+
+            if exhaustive {
+                self.warning_at(&start, "Unreachable match arm.");
+            } else if let Pattern::Literal(value) = pattern {
+                if literals.contains(&value) {
+                    self.warning_at(&start, "Unreachable match arm, the value is matched above.");
+                } else if !guarded {
+                    literals.push(value);
+                }
+            }
+            exhaustive |= pattern.is_irrefutable() && !guarded;
+
+            end_jumps.push(self.match_arm(&pattern, slot)?);
+            self.match_token(TokenType::Comma)?;
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after match arms.")?;
+
+        if !exhaustive {
+            self.emit_opcode(OpCode::NoMatch);
+        }
+        for jump in end_jumps {
+            self.patch_jump(jump)?;
+        }
+
+        // The hidden local's slot now holds the result, which stays on the stack
+        self.function.locals.pop();
+        self.function.scope_depth -= 1;
+        self.function.temporaries = temporaries;
+        Ok(())
+    }
+
+    /// Compile the rest of an arm after its pattern. Returns the jump to the end of
+    /// the match taken once the arm's expression has been evaluated.
+    fn match_arm(&mut self, pattern: &Pattern<'a>, slot: usize) -> Result<usize, Diagnostic> {
+        let mut fail_jumps = Vec::new();
+        self.pattern_tests(pattern, slot, &mut Vec::new(), &mut fail_jumps)?;
+        self.pattern_literals.clear();
+
+        let scope_depth = self.function.scope_depth;
+        self.begin_scope();
+        self.pattern_bindings(pattern, slot, &mut Vec::new())?;
+
+        let guard_jump = match self.match_token(TokenType::If)? {
+            true => {
+                self.expression()?;
+                let jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_opcode(OpCode::Pop);
+                Some(jump)
+            }
+            false => None,
+        };
+        self.consume(TokenType::FatArrow, "Expect '=>' after match pattern.")?;
+        self.expression()?;
+        self.emit_operand_instruction(OpCode::SetLocal, slot as Chunk);
+        self.emit_opcode(OpCode::Pop);
+        self.discard_locals(scope_depth);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        // A failed guard has to discard the bindings before trying the next arm
+        let next_jump = match guard_jump {
+            Some(guard_jump) => {
+                self.patch_jump(guard_jump)?;
+                self.emit_opcode(OpCode::Pop);
+                self.discard_locals(scope_depth);
+                Some(self.emit_jump(OpCode::Jump))
+            }
+            None => None,
+        };
+        while self
+            .function
+            .locals
+            .last()
+            .is_some_and(|local| local.depth.is_some_and(|depth| depth > scope_depth))
+        {
+            self.function.locals.pop();
+        }
+        self.function.scope_depth = scope_depth;
+
+        // Failed tests leave their result on the stack
+        if !fail_jumps.is_empty() {
+            let skip = self.emit_jump(OpCode::Jump);
+            for jump in fail_jumps {
+                self.patch_jump(jump)?;
+            }
+            self.emit_opcode(OpCode::Pop);
+            self.patch_jump(skip)?;
+        }
+        if let Some(next_jump) = next_jump {
+            self.patch_jump(next_jump)?;
+        }
+        Ok(end_jump)
+    }
+
+    fn pattern(&mut self) -> Result<Pattern<'a>, Diagnostic> {
+        self.advance()?;
+        let token = self.previous;
+        match token.typee {
+            TokenType::Identifier if token.lexeme == "_" => Ok(Pattern::Wildcard),
+            TokenType::Identifier if self.check(TokenType::LeftBrace) => self.struct_pattern(token),
+            TokenType::Identifier => Ok(Pattern::Binding(token.lexeme)),
+            TokenType::Nil => Ok(Pattern::Literal(Value::Nil)),
+            TokenType::True => Ok(Pattern::Literal(Value::Bool(true))),
+            TokenType::False => Ok(Pattern::Literal(Value::Bool(false))),
+            TokenType::String => {
+                let lexeme = token.lexeme;
+                let string = Value::Object(self.intern(&unescape(&lexeme[1..lexeme.len() - 1])));
+                self.pattern_literals.push(string);
+                Ok(Pattern::Literal(string))
+            }
+            TokenType::Number | TokenType::Minus => {
+                let start = self.pattern_number()?;
+                let inclusive = match self.current.typee {
+                    TokenType::DotDot => false,
+                    TokenType::DotDotEqual => true,
+                    _ => return Ok(Pattern::Literal(Value::Number(start))),
+                };
+                self.advance()?;
+                self.advance()?;
+                let end = self.pattern_number()?;
+                Ok(Pattern::Range {
+                    start,
+                    end,
+                    inclusive,
+                })
+            }
+            _ => Err(self.error("Expect pattern.")),
+        }
+    }
+
+    /// A number literal in a pattern, possibly negated. The previous token is either
+    /// the number or its minus sign.
+    fn pattern_number(&mut self) -> Result<f64, Diagnostic> {
+        let negative = self.previous.typee == TokenType::Minus;
+        if negative {
+            self.consume(TokenType::Number, "Expect number after '-' in pattern.")?;
+        } else if self.previous.typee != TokenType::Number {
+            return Err(self.error("Expect number in range pattern."));
+        }
+
+        match self.previous.lexeme.parse::<f64>() {
+            Ok(value) if negative => Ok(-value),
+            Ok(value) => Ok(value),
+            Err(_) => Err(self.error("Invalid number literal.")),
+        }
+    }
+
+    fn struct_pattern(&mut self, name: Token<'a>) -> Result<Pattern<'a>, Diagnostic> {
+        self.consume(TokenType::LeftBrace, "Expect '{' after struct name.")?;
+        let mut fields = Vec::new();
+        while !self.check(TokenType::RightBrace) {
+            self.consume(
+                TokenType::Identifier,
+                "Expect field name in struct pattern.",
+            )?;
+            let field = self.previous.lexeme;
+            let pattern = match self.match_token(TokenType::Colon)? {
+                true => self.pattern()?,
+                false => Pattern::Binding(field),
+            };
+            fields.push((field, pattern));
+            if !self.match_token(TokenType::Comma)? {
+                break;
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after struct pattern.")?;
+        Ok(Pattern::Struct { name, fields })
+    }
+
+    /// Push the part of the matched value in `slot` reached by the field names in `path`
+    fn load_path(&mut self, slot: usize, path: &[usize]) {
+        self.emit_operand_instruction(OpCode::GetLocal, slot as Chunk);
+        for field in path {
+            self.emit_constant_instruction(OpCode::GetProperty, *field);
+        }
+    }
+
+    /// Emit the checks that the part of the value at `path` matches, adding a jump to
+    /// `fail_jumps` for each
+    fn pattern_tests(
+        &mut self,
+        pattern: &Pattern<'a>,
+        slot: usize,
+        path: &mut Vec<usize>,
+        fail_jumps: &mut Vec<usize>,
+    ) -> Result<(), Diagnostic> {
+        match pattern {
+            Pattern::Wildcard | Pattern::Binding(_) => return Ok(()),
+            Pattern::Literal(value) => {
+                self.load_path(slot, path);
+                self.emit_constant(*value)?;
+                self.emit_opcode(OpCode::Equal);
+            }
+            Pattern::Range {
+                start,
+                end,
+                inclusive,
+            } => {
+                self.load_path(slot, path);
+                self.emit_constant(Value::Number(*start))?;
+                self.emit_constant(Value::Number(*end))?;
+                self.emit_operand_instruction(OpCode::InRange, (*inclusive).into());
+            }
+            Pattern::Struct { name, fields } => {
+                self.load_path(slot, path);
+                self.named_variable(*name, false)?;
+                self.emit_opcode(OpCode::InstanceOf);
+                fail_jumps.push(self.emit_jump(OpCode::JumpIfFalse));
+                self.emit_opcode(OpCode::Pop);
+
+                // Fields are only read once the value is known to have them
+                for (field, pattern) in fields {
+                    path.push(self.identifier_constant(field)?);
+                    self.pattern_tests(pattern, slot, path, fail_jumps)?;
+                    path.pop();
+                }
+                return Ok(());
+            }
+        }
+        fail_jumps.push(self.emit_jump(OpCode::JumpIfFalse));
+        self.emit_opcode(OpCode::Pop);
+        Ok(())
+    }
+
+    /// Declare a local for every name the pattern binds, initialized from the value
+    fn pattern_bindings(
+        &mut self,
+        pattern: &Pattern<'a>,
+        slot: usize,
+        path: &mut Vec<usize>,
+    ) -> Result<(), Diagnostic> {
+        match pattern {
+            Pattern::Binding(name) => {
+                self.add_local(name, false)?;
+                self.load_path(slot, path);
+                self.mark_initialized();
+            }
+            Pattern::Struct { fields, .. } => {
+                for (field, pattern) in fields {
+                    path.push(self.identifier_constant(field)?);
+                    self.pattern_bindings(pattern, slot, path)?;
+                    path.pop();
+                }
+            }
+            Pattern::Wildcard | Pattern::Literal(_) | Pattern::Range { .. } => {}
+        }
+        Ok(())
+    }
+
+    fn list(&mut self, _can_assign: bool) -> Result<(), Diagnostic> {
+        let mut count = 0;
+        while !self.check(TokenType::RightBracket) {
+            self.with_temporaries(count, Self::expression)?;
+            count += 1;
+            if !self.match_token(TokenType::Comma)? {
+                break;
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after list elements.")?;
+        self.emit_opcode(OpCode::BuildList);
+        self.emit_short(count);
+        Ok(())
+    }
+
+    fn map(&mut self, _can_assign: bool) -> Result<(), Diagnostic> {
+        let mut count = 0;
+        while !self.check(TokenType::RightBrace) {
+            self.with_temporaries(count * 2, Self::expression)?;
+            self.consume(TokenType::Colon, "Expect ':' after map key.")?;
+            self.with_temporaries(count * 2 + 1, Self::expression)?;
+            count += 1;
+            if !self.match_token(TokenType::Comma)? {
+                break;
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after map entries.")?;
+        self.emit_opcode(OpCode::BuildMap);
+        self.emit_short(count);
+        Ok(())
+    }
+
+    fn index(&mut self, can_assign: bool) -> Result<(), Diagnostic> {
+        let bracket = self.previous;
+        self.with_temporaries(1, Self::expression)?;
+        self.consume(TokenType::RightBracket, "Expect ']' after index.")?;
+
+        if can_assign && self.match_token(TokenType::Equal)? {
+            self.with_temporaries(2, Self::expression)?;
+            self.emit_opcode_at(OpCode::SetIndex, bracket);
+        } else {
+            self.emit_opcode_at(OpCode::GetIndex, bracket);
+        }
+        Ok(())
+    }
+
+    fn range(&mut self, _can_assign: bool) -> Result<(), Diagnostic> {
+        let token = self.previous;
+        let operator = token.typee;
+        self.with_temporaries(1, |compiler| {
+            compiler.parse_precedence(Precedence::Range.next())
+        })?;
+
+        match operator {
+            TokenType::DotDot => self.emit_opcode_at(OpCode::Range, token),
+            TokenType::DotDotEqual => self.emit_opcode_at(OpCode::RangeInclusive, token),
+            _ => unreachable!("Unknown range operator {:?}", operator),
+        }
+        Ok(())
+    }
+
+    fn literal(&mut self, _can_assign: bool) -> Result<(), Diagnostic> {
+        match self.previous.typee {
+            TokenType::Nil => self.emit_opcode(OpCode::Nil),
+            TokenType::True => self.emit_opcode(OpCode::True),
+            TokenType::False => self.emit_opcode(OpCode::False),
+            _ => unreachable!("Unknown literal {:?}", self.previous.typee),
+        }
+        Ok(())
+    }
+
+    fn grouping(&mut self, _can_assign: bool) -> Result<(), Diagnostic> {
+        self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after expression.")
+    }
+
+    fn unary(&mut self, _can_assign: bool) -> Result<(), Diagnostic> {
+        let token = self.previous;
+        let operator = token.typee;
+
+        // Compile the operand
+        self.parse_precedence(Precedence::Unary)?;
+
+        match operator {
+            TokenType::Minus => self.emit_opcode_at(OpCode::Negate, token),
+            TokenType::Not => self.emit_opcode_at(OpCode::Not, token),
+            _ => unreachable!("Unknown unary operator {:?}", operator),
+        }
+        Ok(())
+    }
+
+    fn binary(&mut self, _can_assign: bool) -> Result<(), Diagnostic> {
+        let token = self.previous;
+        let operator = token.typee;
+
+        // Operators are left associative so the right operand binds one level tighter
+        let precedence = get_rule(operator).precedence.next();
+        self.with_temporaries(1, |compiler| compiler.parse_precedence(precedence))?;
+
+        match operator {
+            TokenType::Plus => self.emit_opcode_at(OpCode::Add, token),
+            TokenType::Minus => self.emit_opcode_at(OpCode::Subtract, token),
+            TokenType::Star => self.emit_opcode_at(OpCode::Multiply, token),
+            TokenType::Slash => self.emit_opcode_at(OpCode::Divide, token),
+            TokenType::EqualEqual => self.emit_opcode_at(OpCode::Equal, token),
+            TokenType::BangEqual => {
+                self.emit_opcode_at(OpCode::Equal, token);
+                self.emit_opcode_at(OpCode::Not, token);
+            }
+            TokenType::Greater => self.emit_opcode_at(OpCode::Greater, token),
+            TokenType::GreaterEqual => {
+                self.emit_opcode_at(OpCode::Less, token);
+                self.emit_opcode_at(OpCode::Not, token);
+            }
+            TokenType::Less => self.emit_opcode_at(OpCode::Less, token),
+            TokenType::LessEqual => {
+                self.emit_opcode_at(OpCode::Greater, token);
+                self.emit_opcode_at(OpCode::Not, token);
+            }
+            _ => unreachable!("Unknown binary operator {:?}", operator),
+        }
+        Ok(())
+    }
+
+    /// The right operand is skipped when the left one is falsey
+    fn and(&mut self, _can_assign: bool) -> Result<(), Diagnostic> {
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+
+        self.emit_opcode(OpCode::Pop);
+        self.parse_precedence(Precedence::And)?;
+
+        self.patch_jump(end_jump)?;
+        Ok(())
+    }
+
+    /// The right operand is skipped when the left one is truthy
+    fn or(&mut self, _can_assign: bool) -> Result<(), Diagnostic> {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump)?;
+        self.emit_opcode(OpCode::Pop);
+        self.parse_precedence(Precedence::Or)?;
+
+        self.patch_jump(end_jump)?;
+        Ok(())
+    }
+}
+
+fn get_rule<'a>(typee: TokenType) -> ParseRule<'a> {
+    match typee {
+        TokenType::LeftParen => ParseRule::new(
+            Some(Compiler::grouping),
+            Some(Compiler::call),
+            Precedence::Call,
+        ),
+        TokenType::Function => {
+            ParseRule::new(Some(Compiler::function_expression), None, Precedence::None)
+        }
+        TokenType::LeftBracket => ParseRule::new(
+            Some(Compiler::list),
+            Some(Compiler::index),
+            Precedence::Call,
+        ),
+        TokenType::LeftBrace => ParseRule::new(Some(Compiler::map), None, Precedence::None),
+        TokenType::Dot => ParseRule::new(None, Some(Compiler::dot), Precedence::Call),
+        TokenType::Match => {
+            ParseRule::new(Some(Compiler::match_expression), None, Precedence::None)
+        }
+        TokenType::Selff => ParseRule::new(Some(Compiler::self_expression), None, Precedence::None),
+        TokenType::DotDot => ParseRule::new(None, Some(Compiler::range), Precedence::Range),
+        TokenType::DotDotEqual => ParseRule::new(None, Some(Compiler::range), Precedence::Range),
+        TokenType::Minus => ParseRule::new(
+            Some(Compiler::unary),
+            Some(Compiler::binary),
+            Precedence::Term,
+        ),
+        TokenType::Plus => ParseRule::new(None, Some(Compiler::binary), Precedence::Term),
+        TokenType::Slash => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
+        TokenType::Star => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
+        TokenType::Not => ParseRule::new(Some(Compiler::unary), None, Precedence::None),
+        TokenType::EqualEqual => ParseRule::new(None, Some(Compiler::binary), Precedence::Equality),
+        TokenType::BangEqual => ParseRule::new(None, Some(Compiler::binary), Precedence::Equality),
+        TokenType::Greater => ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison),
+        TokenType::GreaterEqual => {
+            ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison)
+        }
+        TokenType::Less => ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison),
+        TokenType::LessEqual => {
+            ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison)
+        }
+        TokenType::And => ParseRule::new(None, Some(Compiler::and), Precedence::And),
+        TokenType::Or => ParseRule::new(None, Some(Compiler::or), Precedence::Or),
+        TokenType::Identifier => ParseRule::new(Some(Compiler::variable), None, Precedence::None),
+        TokenType::String => ParseRule::new(Some(Compiler::string), None, Precedence::None),
+        TokenType::Interpolation => {
+            ParseRule::new(Some(Compiler::interpolation), None, Precedence::None)
+        }
+        TokenType::Number => ParseRule::new(Some(Compiler::number), None, Precedence::None),
+        TokenType::Nil => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
+        TokenType::True => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
+        TokenType::False => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
+        _ => ParseRule::new(None, None, Precedence::None),
+    }
+}
+
+/// The name an imported module is bound to: its file name without the extension. Takes
+/// the path string token including its quotes.
+fn module_name(path: &str) -> &str {
+    let path = &path[1..path.len() - 1];
+    let file = path.rsplit('/').next().unwrap_or(path);
+    match file.rsplit_once('.') {
+        Some((stem, _)) => stem,
+        None => file,
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Captured locals have to be moved off the stack rather than just popped
+fn discard_opcode(local: &Local) -> OpCode {
+    match local.captured {
+        true => OpCode::CloseUpvalue,
+        false => OpCode::Pop,
+    }
+}
+
+/// The arithmetic opcode a compound assignment token applies
+fn compound_operator(typee: TokenType) -> Option<OpCode> {
+    match typee {
+        TokenType::PlusEqual => Some(OpCode::Add),
+        TokenType::MinusEqual => Some(OpCode::Subtract),
+        TokenType::StarEqual => Some(OpCode::Multiply),
+        TokenType::SlashEqual => Some(OpCode::Divide),
+        _ => None,
     }
 }
@@ -0,0 +1,189 @@
+use std::fmt;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    /// A problem that doesn't stop the program from compiling
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// Byte range of the source a diagnostic points at
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+}
+
+/// An error or warning about a program, located in its source
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// `None` when the source of the problem isn't known more precisely than its line
+    pub span: Option<Span>,
+    pub line: usize,
+    /// Counted in chars from 1, known when the span is
+    pub column: Option<usize>,
+    pub notes: Vec<String>,
+    /// File the source was read from, `None` for the main script or code that isn't
+    /// in a file
+    pub file: Option<Box<Path>>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, line: usize) -> Self {
+        Self::new(Severity::Error, message, line)
+    }
+
+    pub fn warning(message: impl Into<String>, line: usize) -> Self {
+        Self::new(Severity::Warning, message, line)
+    }
+
+    fn new(severity: Severity, message: impl Into<String>, line: usize) -> Self {
+        Self {
+            severity,
+            message: message.into(),
+            span: None,
+            line,
+            column: None,
+            notes: Vec::new(),
+            file: None,
+        }
+    }
+
+    /// Point the diagnostic at `span` of `source`, which also decides its line and column
+    pub fn with_span(self, source: &str, span: Span) -> Self {
+        let (line, column) = line_and_column(source, span.start);
+        self.at(span, line, column)
+    }
+
+    /// Point the diagnostic at `span`, which starts at `line` and `column`
+    pub fn at(mut self, span: Span, line: usize, column: usize) -> Self {
+        self.span = Some(span);
+        self.line = line;
+        self.column = Some(column);
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn in_file(mut self, file: &Path) -> Self {
+        self.file = Some(file.into());
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// Render the diagnostic with the offending line of `source` and a caret underline
+    /// below the span
+    pub fn render(&self, source: &str) -> String {
+        let mut rendered = format!("{}: {}\n", self.severity, self.message);
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+        rendered.push_str(&format!("{}--> {}\n", gutter, self.location()));
+
+        let text = match self.points_into(source) {
+            true => source.lines().nth(self.line.wrapping_sub(1)),
+            false => None,
+        };
+        if let Some(text) = text {
+            rendered.push_str(&format!("{} |\n", gutter));
+            rendered.push_str(&format!("{} | {}\n", number, text));
+            if let (Some(span), Some(column)) = (self.span, self.column) {
+                let line_start = source[..span.start.min(source.len())]
+                    .rfind('\n')
+                    .map_or(0, |newline| newline + 1);
+                let line_end = line_start + text.len();
+                let end = span.end.clamp(span.start, line_end.max(span.start));
+                let width = source
+                    .get(span.start..end)
+                    .map_or(0, |spanned| spanned.chars().count())
+                    .max(1);
+                rendered.push_str(&format!(
+                    "{} | {}{}\n",
+                    gutter,
+                    " ".repeat(column - 1),
+                    "^".repeat(width)
+                ));
+            }
+        }
+
+        for note in &self.notes {
+            rendered.push_str(&format!("{} = note: {}\n", gutter, note));
+        }
+        rendered
+    }
+
+    /// Whether the span is where the line and column say it is in `source`, which tells
+    /// whether the diagnostic is about this source at all
+    fn points_into(&self, source: &str) -> bool {
+        match (self.span, self.column) {
+            (Some(span), Some(column)) => {
+                span.end <= source.len()
+                    && source.is_char_boundary(span.start)
+                    && line_and_column(source, span.start) == (self.line, column)
+            }
+            _ => true,
+        }
+    }
+
+    /// Where the diagnostic points, as `file:line:column`
+    fn location(&self) -> String {
+        let mut location = match &self.file {
+            Some(file) => format!("{}:{}", file.display(), self.line),
+            None => format!("line {}", self.line),
+        };
+        if let Some(column) = self.column {
+            location.push_str(&format!(":{}", column));
+        }
+        location
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {}: {}",
+            self.location(),
+            self.severity,
+            self.message
+        )?;
+        for note in &self.notes {
+            write!(f, "\n  note: {}", note)?;
+        }
+        Ok(())
+    }
+}
+
+/// Line and column of the byte at `offset`, both counted from 1 with columns in chars
+pub fn line_and_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = match source.get(..offset) {
+        Some(before) => before,
+        None => source,
+    };
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
@@ -1,52 +1,136 @@
-use crate::{ByteCode, OpCode};
+use crate::{ByteCode, Heap, Object, OpCode, Value};
 
-pub fn disassembler(bytecode: &ByteCode, filename: &str) {
+pub fn disassembler(bytecode: &ByteCode, heap: &Heap, filename: &str) {
     println!("== {} ==", filename);
 
     let mut offset = 0;
     while offset < bytecode.chunk_count() {
-        offset = disassemble_instruction(bytecode, offset);
+        offset = disassemble_instruction(bytecode, heap, offset);
     }
 }
 
-pub fn disassemble_instruction(bytecode: &ByteCode, offset: usize) -> usize {
+/// List a function's bytecode before and after the optimizer rewrote it
+pub fn disassemble_optimized(before: &ByteCode, after: &ByteCode, heap: &Heap, name: &str) {
+    disassembler(before, heap, &format!("{} (before optimizing)", name));
+    disassembler(after, heap, &format!("{} (optimized)", name));
+    println!(
+        "{} -> {} chunks, {} -> {} constants",
+        before.chunk_count(),
+        after.chunk_count(),
+        before.get_constants().len(),
+        after.get_constants().len()
+    );
+}
+
+/// The `line:column` a chunk was compiled from, or just the line when its column isn't
+/// known
+fn source_position(bytecode: &ByteCode, offset: usize) -> Option<String> {
+    let line = bytecode.get_line(offset)?;
+    match bytecode.get_span(offset) {
+        Some(span) => Some(format!("{}:{}", line, span.column)),
+        None => Some(line.to_string()),
+    }
+}
+
+/// Print the instruction at `offset` and return the offset of the next one.
+/// Malformed bytecode is reported in the listing rather than panicking.
+pub fn disassemble_instruction(bytecode: &ByteCode, heap: &Heap, offset: usize) -> usize {
     print!("{:04} ", offset);
-    if offset > 0 {
-        let line = match bytecode.get_line(offset) {
-            Some(l) => l,
-            None => panic!("Invalid line number {}", offset),
-        };
-        let last_line = match bytecode.get_line(offset - 1) {
-            Some(l) => l,
-            None => panic!("Invalid line number {}", offset - 1),
-        };
-        if line == last_line {
-            print!("   | ");
-        } else {
-            print!("{:4} ", line);
-        }
+    let position = source_position(bytecode, offset);
+    if offset > 0 && position.is_some() && position == source_position(bytecode, offset - 1) {
+        print!("{:>8} ", "|");
     } else {
-        match bytecode.get_line(offset) {
-            Some(line) => print!("{:4} ", line),
-            None => panic!("Invalid line number {}", offset),
+        match position {
+            Some(position) => print!("{:>8} ", position),
+            None => print!("{:>8} ", "?"),
         }
     };
 
-    if let Some(chunk) = bytecode.get_chunk(offset) {
-        match OpCode::try_from(*chunk) {
-            Ok(opcode) => match opcode {
-                OpCode::Constant => constant_instruction("CONSTANT", bytecode, offset),
-                OpCode::Add => simple_instruction("ADD", offset),
-                OpCode::Subtract => simple_instruction("SUBTRACT", offset),
-                OpCode::Multiply => simple_instruction("MULTIPLY", offset),
-                OpCode::Divide => simple_instruction("DIVIDE", offset),
-                OpCode::Negate => simple_instruction("NEGATE", offset),
-                OpCode::Return => simple_instruction("RETURN", offset),
-            },
-            Err(_) => panic!("Unknown opcode {}", chunk),
+    let chunk = match bytecode.get_chunk(offset) {
+        Some(chunk) => *chunk,
+        None => {
+            println!("Invalid chunk offset {}", offset);
+            return bytecode.chunk_count();
+        }
+    };
+
+    match OpCode::try_from(chunk) {
+        Ok(opcode) => match opcode {
+            OpCode::Constant => constant_instruction("CONSTANT", bytecode, heap, offset),
+            OpCode::Add => simple_instruction("ADD", offset),
+            OpCode::Subtract => simple_instruction("SUBTRACT", offset),
+            OpCode::Multiply => simple_instruction("MULTIPLY", offset),
+            OpCode::Divide => simple_instruction("DIVIDE", offset),
+            OpCode::Negate => simple_instruction("NEGATE", offset),
+            OpCode::Return => simple_instruction("RETURN", offset),
+            OpCode::Nil => simple_instruction("NIL", offset),
+            OpCode::True => simple_instruction("TRUE", offset),
+            OpCode::False => simple_instruction("FALSE", offset),
+            OpCode::Equal => simple_instruction("EQUAL", offset),
+            OpCode::Greater => simple_instruction("GREATER", offset),
+            OpCode::Less => simple_instruction("LESS", offset),
+            OpCode::Not => simple_instruction("NOT", offset),
+            OpCode::Pop => simple_instruction("POP", offset),
+            OpCode::Jump => jump_instruction("JUMP", bytecode, offset),
+            OpCode::JumpIfFalse => jump_instruction("JUMP_IF_FALSE", bytecode, offset),
+            OpCode::ToString => simple_instruction("TO_STRING", offset),
+            OpCode::DefineGlobal => {
+                long_constant_instruction("DEFINE_GLOBAL", bytecode, heap, offset)
+            }
+            OpCode::DefineMutableGlobal => {
+                long_constant_instruction("DEFINE_MUTABLE_GLOBAL", bytecode, heap, offset)
+            }
+            OpCode::GetGlobal => long_constant_instruction("GET_GLOBAL", bytecode, heap, offset),
+            OpCode::SetGlobal => long_constant_instruction("SET_GLOBAL", bytecode, heap, offset),
+            OpCode::GetLocal => operand_instruction("GET_LOCAL", bytecode, offset),
+            OpCode::SetLocal => operand_instruction("SET_LOCAL", bytecode, offset),
+            OpCode::Loop => loop_instruction("LOOP", bytecode, offset),
+            OpCode::BuildList => short_instruction("BUILD_LIST", bytecode, offset),
+            OpCode::BuildMap => short_instruction("BUILD_MAP", bytecode, offset),
+            OpCode::GetIndex => simple_instruction("GET_INDEX", offset),
+            OpCode::SetIndex => simple_instruction("SET_INDEX", offset),
+            OpCode::Range => simple_instruction("RANGE", offset),
+            OpCode::RangeInclusive => simple_instruction("RANGE_INCLUSIVE", offset),
+            OpCode::GetIter => simple_instruction("GET_ITER", offset),
+            OpCode::ForIter => jump_instruction("FOR_ITER", bytecode, offset),
+            OpCode::Call => operand_instruction("CALL", bytecode, offset),
+            OpCode::Closure => closure_instruction("CLOSURE", bytecode, heap, offset),
+            OpCode::GetUpvalue => operand_instruction("GET_UPVALUE", bytecode, offset),
+            OpCode::SetUpvalue => operand_instruction("SET_UPVALUE", bytecode, offset),
+            OpCode::CloseUpvalue => simple_instruction("CLOSE_UPVALUE", offset),
+            OpCode::Struct => long_constant_instruction("STRUCT", bytecode, heap, offset),
+            OpCode::Field => member_instruction("FIELD", bytecode, heap, offset),
+            OpCode::Method => member_instruction("METHOD", bytecode, heap, offset),
+            OpCode::GetProperty => {
+                long_constant_instruction("GET_PROPERTY", bytecode, heap, offset)
+            }
+            OpCode::SetProperty => {
+                long_constant_instruction("SET_PROPERTY", bytecode, heap, offset)
+            }
+            OpCode::InstanceOf => simple_instruction("INSTANCE_OF", offset),
+            OpCode::InRange => operand_instruction("IN_RANGE", bytecode, offset),
+            OpCode::NoMatch => simple_instruction("NO_MATCH", offset),
+            OpCode::Export => long_constant_instruction("EXPORT", bytecode, heap, offset),
+            OpCode::Import => long_constant_instruction("IMPORT", bytecode, heap, offset),
+            OpCode::ConstantLong => {
+                long_constant_instruction("CONSTANT_LONG", bytecode, heap, offset)
+            }
+            OpCode::AddConstant => {
+                long_constant_instruction("ADD_CONSTANT", bytecode, heap, offset)
+            }
+            OpCode::IncrementLocal => {
+                increment_instruction("INCREMENT_LOCAL", bytecode, heap, offset)
+            }
+            OpCode::LessJumpIfFalse => jump_instruction("LESS_JUMP_IF_FALSE", bytecode, offset),
+            OpCode::GreaterJumpIfFalse => {
+                jump_instruction("GREATER_JUMP_IF_FALSE", bytecode, offset)
+            }
+            OpCode::EqualJumpIfFalse => jump_instruction("EQUAL_JUMP_IF_FALSE", bytecode, offset),
+        },
+        Err(_) => {
+            println!("Unknown opcode {}", chunk);
+            offset + 1
         }
-    } else {
-        panic!("Invalid chunk offset {}", offset);
     }
 }
 
@@ -55,15 +139,133 @@ fn simple_instruction(name: &str, offset: usize) -> usize {
     offset + 1
 }
 
-fn constant_instruction(name: &str, bytecode: &ByteCode, offset: usize) -> usize {
-    let index = match bytecode.get_chunk(offset + 1) {
-        Some(i) => i,
-        None => panic!("Invalid chunk offset {}", offset),
+fn operand_instruction(name: &str, bytecode: &ByteCode, offset: usize) -> usize {
+    match bytecode.get_chunk(offset + 1) {
+        Some(operand) => println!("{:16} {:4}", name, operand),
+        None => println!("{:16} <missing operand>", name),
+    }
+    offset + 2
+}
+
+/// An instruction with a two chunk operand
+fn short_instruction(name: &str, bytecode: &ByteCode, offset: usize) -> usize {
+    match bytecode.get_short(offset + 1) {
+        Some(operand) => println!("{:16} {:4}", name, operand),
+        None => println!("{:16} <missing operand>", name),
+    }
+    offset + 3
+}
+
+fn jump_instruction(name: &str, bytecode: &ByteCode, offset: usize) -> usize {
+    match bytecode.get_short(offset + 1) {
+        Some(jump) => println!("{:16} {:4} -> {}", name, offset, offset + 3 + jump),
+        None => println!("{:16} <missing operand>", name),
+    }
+    offset + 3
+}
+
+fn loop_instruction(name: &str, bytecode: &ByteCode, offset: usize) -> usize {
+    match bytecode.get_short(offset + 1) {
+        Some(jump) => match (offset + 3).checked_sub(jump) {
+            Some(target) => println!("{:16} {:4} -> {}", name, offset, target),
+            None => println!("{:16} {:4} -> <invalid target>", name, offset),
+        },
+        None => println!("{:16} <missing operand>", name),
+    }
+    offset + 3
+}
+
+/// An instruction whose operand is a one chunk constant index
+fn constant_instruction(name: &str, bytecode: &ByteCode, heap: &Heap, offset: usize) -> usize {
+    let index = bytecode
+        .get_chunk(offset + 1)
+        .map(|index| usize::from(*index));
+    print_constant(name, bytecode, heap, index, "");
+    offset + 2
+}
+
+/// An instruction whose operand is a three chunk constant index
+fn long_constant_instruction(name: &str, bytecode: &ByteCode, heap: &Heap, offset: usize) -> usize {
+    print_constant(name, bytecode, heap, bytecode.get_long(offset + 1), "");
+    offset + 4
+}
+
+fn print_constant(
+    name: &str,
+    bytecode: &ByteCode,
+    heap: &Heap,
+    index: Option<usize>,
+    suffix: &str,
+) {
+    match index {
+        Some(index) => match bytecode.get_constant(index) {
+            Some(constant) => println!(
+                "{:16} {} '{}'{}",
+                name,
+                index,
+                heap.display(*constant),
+                suffix
+            ),
+            None => println!("{:16} {} <invalid constant>{}", name, index, suffix),
+        },
+        None => println!("{:16} <missing operand>", name),
+    }
+}
+
+/// A constant instruction followed by whether the struct member is public
+fn member_instruction(name: &str, bytecode: &ByteCode, heap: &Heap, offset: usize) -> usize {
+    let public = match bytecode.get_chunk(offset + 4) {
+        Some(0) => "",
+        Some(_) => " public",
+        None => " <missing operand>",
     };
-    let constant = match bytecode.get_constant(*index) {
-        Some(c) => c,
-        None => panic!("Invalid constant index {}", index),
+    print_constant(name, bytecode, heap, bytecode.get_long(offset + 1), public);
+    offset + 5
+}
+
+/// A constant instruction followed by the slot of the local the constant is added to
+fn increment_instruction(name: &str, bytecode: &ByteCode, heap: &Heap, offset: usize) -> usize {
+    let slot = match bytecode.get_chunk(offset + 4) {
+        Some(slot) => format!(" to slot {}", slot),
+        None => " <missing operand>".to_string(),
     };
-    println!("{:16} {} '{}'", name, index, constant);
-    offset + 2
+    print_constant(name, bytecode, heap, bytecode.get_long(offset + 1), &slot);
+    offset + 5
+}
+
+/// Print the function constant followed by a line for every variable the closure
+/// captures, either a local slot of the enclosing function or one of its upvalues
+fn closure_instruction(name: &str, bytecode: &ByteCode, heap: &Heap, offset: usize) -> usize {
+    let upvalue_count = match bytecode.get_long(offset + 1) {
+        Some(index) => match bytecode.get_constant(index) {
+            Some(Value::Object(function)) => match heap.get(*function) {
+                Some(Object::Function(function)) => function.upvalue_count,
+                _ => 0,
+            },
+            _ => 0,
+        },
+        None => 0,
+    };
+
+    let mut offset = long_constant_instruction(name, bytecode, heap, offset);
+    for _ in 0..upvalue_count {
+        match (bytecode.get_chunk(offset), bytecode.get_chunk(offset + 1)) {
+            (Some(is_local), Some(index)) => {
+                let kind = match is_local {
+                    0 => "upvalue",
+                    _ => "local",
+                };
+                println!(
+                    "{:04}        |                     {} {}",
+                    offset, kind, index
+                );
+            }
+            _ => println!(
+                "{:04}        |                     <missing operand>",
+                offset
+            ),
+        }
+        offset += 2;
+    }
+    offset
 }
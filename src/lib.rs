@@ -1,13 +1,27 @@
 pub mod bytecode;
 pub mod compiler;
+pub mod diagnostic;
 pub mod disassembler;
+pub mod module;
+pub mod native;
+pub mod object;
+pub mod optimizer;
 pub mod scanner;
+pub mod serialize;
 pub mod value;
+pub mod verifier;
 pub mod vm;
 
 pub use crate::bytecode::*;
 pub use crate::compiler::*;
+pub use crate::diagnostic::*;
 pub use crate::disassembler::*;
+pub use crate::module::*;
+pub use crate::native::*;
+pub use crate::object::*;
+pub use crate::optimizer::*;
 pub use crate::scanner::*;
+pub use crate::serialize::*;
 pub use crate::value::*;
+pub use crate::verifier::*;
 pub use crate::vm::*;
@@ -1,47 +1,194 @@
 use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::{env, fs, io, process::exit};
 
-use raven_lang::{InterpretError, VirtualMachine};
+use raven_lang::{
+    compile_with_imports, is_bytecode, optimize, serialize, Diagnostic, Heap, InterpretError,
+    ModuleLoader, OptimizationLevel, VirtualMachine, BYTECODE_EXTENSION,
+};
 
-fn repl() {
+const USAGE: &str =
+    "Usage: raven [-O<level>] [run] [path]\n       raven [-O<level>] compile path [-o output]";
+
+fn repl(level: OptimizationLevel) {
     let mut vm = VirtualMachine::new();
+    vm.set_optimization_level(level);
 
     loop {
         print!("> ");
         io::stdout().flush().unwrap();
 
         let mut line = String::new();
-        io::stdin()
+        let bytes = io::stdin()
             .read_line(&mut line)
             .expect("Failed to read line");
+        if bytes == 0 {
+            println!();
+            break;
+        }
 
-        if let Err(e) = vm.interpret(&line) {
-            eprintln!("{:?}", e);
+        let result = vm.interpret(&line);
+        report(&vm.take_warnings(), &line);
+        if let Err(e) = result {
+            report_error(&e, &line);
             io::stderr().flush().unwrap();
         }
     }
 }
 
-fn run_file(path: &str) {
+/// Run a source file or a compiled file, told apart by their first bytes
+fn run_file(path: &str, level: OptimizationLevel) {
     let mut vm = VirtualMachine::new();
+    vm.set_optimization_level(level);
+    vm.set_script_path(Path::new(path));
+
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Could not read '{}': {}", path, e);
+            exit(74);
+        }
+    };
+    if is_bytecode(&bytes) {
+        if let Err(e) = vm.run_bytecode(&bytes) {
+            report_error(&e, "");
+            exit_code(&e);
+        }
+        return;
+    }
+
+    let source = match String::from_utf8(bytes) {
+        Ok(source) => source,
+        Err(_) => {
+            eprintln!("Could not read '{}': not valid UTF-8", path);
+            exit(65);
+        }
+    };
+    let result = vm.interpret(&source);
+    report(&vm.take_warnings(), &source);
+    if let Err(e) = result {
+        report_error(&e, &source);
+        exit_code(&e);
+    }
+}
+
+/// Compile the source file at `path` and write the bytecode to `output`
+fn compile_file(path: &str, output: &Path, level: OptimizationLevel) {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Could not read '{}': {}", path, e);
+            exit(74);
+        }
+    };
+    let mut heap = Heap::new();
+    let mut loader = ModuleLoader::default();
+    loader.set_script_path(Path::new(path));
+
+    let script = match compile_with_imports(&source, &mut heap, &mut loader) {
+        Ok((script, warnings)) => {
+            report(&warnings, &source);
+            script
+        }
+        Err(diagnostics) => {
+            report(&diagnostics, &source);
+            exit(65);
+        }
+    };
+    optimize(&mut heap, script, level);
+    let bytes = match serialize(&heap, script, loader.script_path()) {
+        Ok(bytes) => bytes,
+        Err(message) => {
+            eprintln!("Could not compile '{}': {}", path, message);
+            exit(65);
+        }
+    };
+    if let Err(e) = fs::write(output, bytes) {
+        eprintln!("Could not write '{}': {}", output.display(), e);
+        exit(74);
+    }
+}
 
-    let source = fs::read_to_string(path).expect("Should have been able to read the file");
-    if let Err(e) = vm.interpret(&source) {
-        match e {
-            InterpretError::CompileError => exit(65),
-            InterpretError::RuntimeError => exit(70),
+fn exit_code(error: &InterpretError) -> ! {
+    match error {
+        InterpretError::CompileError(_)
+        | InterpretError::LoadError(_)
+        | InterpretError::VerifyError(_) => exit(65),
+        InterpretError::RuntimeError { .. } => exit(70),
+    }
+}
+
+fn report_error(error: &InterpretError, source: &str) {
+    match error {
+        InterpretError::LoadError(e) => eprintln!("{}", e),
+        InterpretError::VerifyError(e) => eprintln!("{}", e),
+        _ => {}
+    }
+    report(error.diagnostics(), source);
+    if !error.trace().is_empty() {
+        eprintln!("stack trace:");
+        for frame in error.trace() {
+            eprintln!("{}", frame);
         }
     }
 }
 
+/// Print the diagnostics with the lines they point at, read from the file each one is
+/// about or else taken from `source`
+fn report(diagnostics: &[Diagnostic], source: &str) {
+    for diagnostic in diagnostics {
+        let file_source = diagnostic
+            .file
+            .as_ref()
+            .and_then(|file| fs::read_to_string(file).ok());
+        let source = match &file_source {
+            Some(file_source) => file_source,
+            None => source,
+        };
+        eprintln!("{}", diagnostic.render(source));
+    }
+}
+
+/// The level an `-O<level>` flag asks for, `-O` alone meaning `-O1`
+fn optimization_level(flag: &str) -> Option<OptimizationLevel> {
+    match flag.strip_prefix("-O")? {
+        "" => Some(OptimizationLevel::Basic),
+        level => OptimizationLevel::try_from(level.parse::<u8>().ok()?).ok(),
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() == 1 {
-        repl();
-    } else if args.len() == 2 {
-        run_file(&args[1]);
-    } else {
-        eprintln!("Usage: raven [path]");
-        exit(64);
+    let mut level = OptimizationLevel::None;
+    let mut rest = Vec::new();
+    for arg in args.iter().skip(1) {
+        match optimization_level(arg) {
+            Some(flag) => level = flag,
+            None if arg.starts_with("-O") => {
+                eprintln!(
+                    "Unknown optimization level '{}', expected -O0, -O1 or -O2",
+                    arg
+                );
+                exit(64);
+            }
+            None => rest.push(arg.as_str()),
+        }
+    }
+
+    match rest[..] {
+        [] => repl(level),
+        ["run"] => usage(),
+        ["run", path] | [path] if path != "compile" => run_file(path, level),
+        ["compile", path] => {
+            let output = Path::new(path).with_extension(BYTECODE_EXTENSION);
+            compile_file(path, &output, level)
+        }
+        ["compile", path, "-o", output] => compile_file(path, &PathBuf::from(output), level),
+        _ => usage(),
     }
 }
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(64);
+}
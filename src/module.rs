@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::ObjRef;
+
+/// Extension of source files, added to imports that don't name one
+pub const SOURCE_EXTENSION: &str = "rv";
+
+/// Finds the files a program imports and remembers the modules compiled from them, so
+/// every file is compiled once however often it is imported
+pub struct ModuleLoader {
+    /// Imports from code that isn't in a file, such as the REPL, resolve against this
+    root: PathBuf,
+    /// File of the main script, if it has one
+    script: Option<PathBuf>,
+    /// Keyed by canonical path
+    modules: HashMap<PathBuf, ObjRef>,
+    /// Files being compiled, outermost first
+    loading: Vec<PathBuf>,
+}
+
+impl ModuleLoader {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        let root = root.into();
+        Self {
+            root: fs::canonicalize(&root).unwrap_or(root),
+            script: None,
+            modules: HashMap::new(),
+            loading: Vec::new(),
+        }
+    }
+
+    /// Resolve the main script's imports relative to the file at `path`
+    pub fn set_script_path(&mut self, path: &Path) {
+        let script = fs::canonicalize(path).unwrap_or(path.to_path_buf());
+        if let Some(directory) = script.parent() {
+            self.root = directory.to_path_buf();
+        }
+        self.script = Some(script);
+    }
+
+    pub fn script_path(&self) -> Option<&Path> {
+        self.script.as_deref()
+    }
+
+    /// Find the file `import` refers to from the file `importer`, or from the root
+    /// when the importing code isn't in a file
+    pub fn resolve(&self, importer: Option<&Path>, import: &str) -> Result<PathBuf, String> {
+        let directory = match importer.and_then(Path::parent) {
+            Some(directory) => directory,
+            None => &self.root,
+        };
+        let mut path = directory.join(import);
+        if path.extension().is_none() {
+            path.set_extension(SOURCE_EXTENSION);
+        }
+        match fs::canonicalize(&path) {
+            Ok(path) => Ok(path),
+            Err(e) => Err(format!("Could not find module '{}': {}.", import, e)),
+        }
+    }
+
+    pub fn get(&self, path: &Path) -> Option<ObjRef> {
+        self.modules.get(path).copied()
+    }
+
+    pub fn insert(&mut self, path: PathBuf, module: ObjRef) {
+        self.modules.insert(path, module);
+    }
+
+    /// Mark the file as being compiled. Fails with the chain of imports leading back
+    /// to it, one line per import, if it already is.
+    pub fn begin(&mut self, path: PathBuf) -> Result<(), Vec<String>> {
+        if let Some(start) = self.loading.iter().position(|loading| *loading == path) {
+            let cycle: Vec<&PathBuf> = self.loading[start..]
+                .iter()
+                .chain(std::iter::once(&path))
+                .collect();
+            return Err(cycle
+                .windows(2)
+                .map(|pair| {
+                    format!(
+                        "{} imports {}.",
+                        self.display_path(pair[0]),
+                        self.display_path(pair[1])
+                    )
+                })
+                .collect());
+        }
+        self.loading.push(path);
+        Ok(())
+    }
+
+    /// The innermost file being compiled is done
+    pub fn end(&mut self) {
+        self.loading.pop();
+    }
+
+    /// The path relative to the root, for messages
+    pub fn display_path(&self, path: &Path) -> String {
+        path.strip_prefix(&self.root)
+            .unwrap_or(path)
+            .display()
+            .to_string()
+    }
+}
+
+impl Default for ModuleLoader {
+    fn default() -> Self {
+        Self::new(".")
+    }
+}
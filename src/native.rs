@@ -0,0 +1,47 @@
+use crate::{Heap, ObjNative, Object, Value};
+
+/// Functions implemented in Rust that every program can call
+pub fn natives() -> Vec<ObjNative> {
+    vec![
+        ObjNative {
+            name: "print",
+            arity: None,
+            function: print,
+        },
+        ObjNative {
+            name: "len",
+            arity: Some(1),
+            function: len,
+        },
+    ]
+}
+
+/// Print the arguments separated by spaces, followed by a newline
+fn print(heap: &mut Heap, arguments: &[Value]) -> Result<Value, String> {
+    let line: Vec<String> = arguments
+        .iter()
+        .map(|argument| heap.display(*argument).to_string())
+        .collect();
+    println!("{}", line.join(" "));
+    Ok(Value::Nil)
+}
+
+/// Number of characters in a string, items in a list or entries in a map
+fn len(heap: &mut Heap, arguments: &[Value]) -> Result<Value, String> {
+    let length = match arguments[0] {
+        Value::Object(object) => match heap.get(object) {
+            Some(Object::String(string)) => Some(string.chars.chars().count()),
+            Some(Object::List(list)) => Some(list.items.len()),
+            Some(Object::Map(map)) => Some(map.entries.len()),
+            _ => None,
+        },
+        _ => None,
+    };
+    match length {
+        Some(length) => Ok(Value::Number(length as f64)),
+        None => Err(format!(
+            "Can't take the length of a {}.",
+            heap.type_name(&arguments[0])
+        )),
+    }
+}
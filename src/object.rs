@@ -0,0 +1,651 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::mem::{size_of, size_of_val};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::{ByteCode, Chunk, Value};
+
+/// Handle to an object living on the virtual machine's heap.
+///
+/// Handles are compared by identity, two handles are equal only when they refer to
+/// the same heap slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ObjRef(usize);
+
+impl ObjRef {
+    pub fn new(index: usize) -> Self {
+        Self(index)
+    }
+
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+pub enum Object {
+    String(ObjString),
+    Range(ObjRange),
+    List(ObjList),
+    Map(ObjMap),
+    Iterator(ObjIterator),
+    Function(ObjFunction),
+    Native(ObjNative),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Struct(ObjStruct),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+    Module(ObjModule),
+}
+
+impl Object {
+    /// Rough number of bytes the object takes up, used to decide when to collect
+    fn size(&self) -> usize {
+        let owned = match self {
+            Object::String(string) => string.chars.len(),
+            Object::List(list) => list.items.capacity() * size_of::<Value>(),
+            // Every entry is also in the index
+            Object::Map(map) => map.entries.capacity() * size_of::<(Value, Value, usize)>(),
+            Object::Function(function) => {
+                function.bytecode.chunk_count() * size_of::<Chunk>() * 2
+                    + size_of_val(function.bytecode.get_constants())
+            }
+            Object::Closure(closure) => closure.upvalues.capacity() * size_of::<ObjRef>(),
+            Object::Struct(structure) => {
+                structure.fields.capacity() * size_of::<StructMember>()
+                    + structure.methods.len() * size_of::<(ObjRef, Method)>()
+            }
+            Object::Instance(instance) => instance.fields.capacity() * size_of::<Value>(),
+            Object::Module(module) => module.globals.len() * size_of::<(ObjRef, Global)>(),
+            Object::Range(_)
+            | Object::Iterator(_)
+            | Object::Native(_)
+            | Object::Upvalue(_)
+            | Object::BoundMethod(_) => 0,
+        };
+        size_of::<Object>() + owned
+    }
+
+    /// Call `visit` with every value the object refers to
+    pub(crate) fn trace(&self, mut visit: impl FnMut(Value)) {
+        match self {
+            Object::String(_) | Object::Range(_) | Object::Native(_) => {}
+            Object::List(list) => list.items.iter().for_each(|item| visit(*item)),
+            Object::Map(map) => {
+                for (key, value) in &map.entries {
+                    visit(*key);
+                    visit(*value);
+                }
+            }
+            Object::Iterator(iterator) => match iterator {
+                ObjIterator::Range { .. } => {}
+                ObjIterator::List { list, .. } => visit(Value::Object(*list)),
+                ObjIterator::MapKeys { map, .. } => visit(Value::Object(*map)),
+                ObjIterator::Chars { string, .. } => visit(Value::Object(*string)),
+            },
+            Object::Function(function) => {
+                if let Some(name) = function.name {
+                    visit(Value::Object(name));
+                }
+                function
+                    .bytecode
+                    .get_constants()
+                    .iter()
+                    .for_each(|constant| visit(*constant));
+            }
+            Object::Closure(closure) => {
+                visit(Value::Object(closure.function));
+                visit(Value::Object(closure.module));
+                for upvalue in &closure.upvalues {
+                    visit(Value::Object(*upvalue));
+                }
+            }
+            Object::Upvalue(ObjUpvalue::Open(_)) => {}
+            Object::Upvalue(ObjUpvalue::Closed(value)) => visit(*value),
+            Object::Struct(structure) => {
+                visit(Value::Object(structure.name));
+                visit(Value::Object(structure.module));
+                for field in &structure.fields {
+                    visit(Value::Object(field.name));
+                }
+                for (name, method) in &structure.methods {
+                    visit(Value::Object(*name));
+                    visit(Value::Object(method.closure));
+                }
+            }
+            Object::Instance(instance) => {
+                visit(Value::Object(instance.structure));
+                instance.fields.iter().for_each(|field| visit(*field));
+            }
+            Object::BoundMethod(bound) => {
+                visit(bound.receiver);
+                visit(Value::Object(bound.method));
+            }
+            Object::Module(module) => {
+                visit(Value::Object(module.name));
+                if let Some(script) = module.script {
+                    visit(Value::Object(script));
+                }
+                for (name, global) in &module.globals {
+                    visit(Value::Object(*name));
+                    visit(global.value);
+                }
+            }
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Object::String(_) => "string",
+            Object::Range(_) => "range",
+            Object::List(_) => "list",
+            Object::Map(_) => "map",
+            Object::Iterator(_) => "iterator",
+            Object::Function(_)
+            | Object::Native(_)
+            | Object::Closure(_)
+            | Object::BoundMethod(_) => "function",
+            Object::Struct(_) => "struct",
+            Object::Instance(_) => "instance",
+            Object::Module(_) => "module",
+            Object::Upvalue(_) => "upvalue",
+        }
+    }
+}
+
+pub struct ObjString {
+    pub chars: Rc<str>,
+}
+
+pub struct ObjRange {
+    pub start: f64,
+    pub end: f64,
+    pub inclusive: bool,
+}
+
+pub struct ObjList {
+    pub items: Vec<Value>,
+}
+
+/// Key under which a value is stored in a map. Objects hash by identity, which for
+/// interned strings is the same as hashing by contents.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Nil,
+    Bool(bool),
+    Number(u64),
+    Object(ObjRef),
+}
+
+impl From<Value> for MapKey {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Nil => MapKey::Nil,
+            Value::Bool(value) => MapKey::Bool(value),
+            // Make 0 and -0 the same key
+            Value::Number(0.0) => MapKey::Number(0.0f64.to_bits()),
+            Value::Number(value) => MapKey::Number(value.to_bits()),
+            Value::Object(object) => MapKey::Object(object),
+        }
+    }
+}
+
+/// A map that remembers insertion order so iterating over it is deterministic
+pub struct ObjMap {
+    pub entries: Vec<(Value, Value)>,
+    indices: HashMap<MapKey, usize>,
+}
+
+impl ObjMap {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            indices: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, key: Value) -> Option<Value> {
+        self.indices
+            .get(&MapKey::from(key))
+            .map(|index| self.entries[*index].1)
+    }
+
+    pub fn insert(&mut self, key: Value, value: Value) {
+        match self.indices.get(&MapKey::from(key)) {
+            Some(index) => self.entries[*index].1 = value,
+            None => {
+                self.indices.insert(MapKey::from(key), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+}
+
+impl Default for ObjMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Position of a `for` loop within the object it iterates over
+pub enum ObjIterator {
+    Range {
+        next: f64,
+        end: f64,
+        inclusive: bool,
+    },
+    List {
+        list: ObjRef,
+        index: usize,
+    },
+    MapKeys {
+        map: ObjRef,
+        index: usize,
+    },
+    /// `offset` is in bytes into the string
+    Chars {
+        string: ObjRef,
+        offset: usize,
+    },
+}
+
+pub struct ObjFunction {
+    /// `None` for the top-level script
+    pub name: Option<ObjRef>,
+    pub arity: usize,
+    /// Number of variables closures over this function capture
+    pub upvalue_count: usize,
+    /// Shared so call frames can read it while the heap is being mutated
+    pub bytecode: Rc<ByteCode>,
+}
+
+/// A function together with the variables it captured when it was created
+pub struct ObjClosure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+    /// Module whose globals the closure reads and writes
+    pub module: ObjRef,
+}
+
+/// A captured variable. It refers to the stack slot of the local while that is still
+/// in scope and holds the value itself once the local has gone out of scope.
+pub enum ObjUpvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+pub struct StructMember {
+    pub name: ObjRef,
+    pub public: bool,
+}
+
+/// A struct type. Calling it creates an instance with the arguments as its fields, in
+/// declaration order.
+pub struct ObjStruct {
+    pub name: ObjRef,
+    /// Private members are only accessible from code in this module
+    pub module: ObjRef,
+    pub fields: Vec<StructMember>,
+    /// Closures keyed by their interned name
+    pub methods: HashMap<ObjRef, Method>,
+}
+
+pub struct Method {
+    pub closure: ObjRef,
+    pub public: bool,
+}
+
+impl ObjStruct {
+    pub fn new(name: ObjRef, module: ObjRef) -> Self {
+        Self {
+            name,
+            module,
+            fields: Vec::new(),
+            methods: HashMap::new(),
+        }
+    }
+
+    /// Index of the field in every instance's `fields`
+    pub fn field_index(&self, name: ObjRef) -> Option<usize> {
+        self.fields.iter().position(|field| field.name == name)
+    }
+}
+
+pub struct ObjInstance {
+    pub structure: ObjRef,
+    /// In the order the struct declares them
+    pub fields: Vec<Value>,
+}
+
+/// A method together with the instance it was accessed on
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+pub struct Global {
+    pub value: Value,
+    pub mutable: bool,
+    /// Whether modules importing this one can read it
+    pub public: bool,
+}
+
+/// The top-level bindings of a file
+pub struct ObjModule {
+    pub name: ObjRef,
+    /// Top-level code of the module, taken when the module is first imported so it
+    /// only runs once
+    pub script: Option<ObjRef>,
+    /// File the module was compiled from, `None` for code that isn't in a file
+    pub file: Option<PathBuf>,
+    /// Keyed by the interned name
+    pub globals: HashMap<ObjRef, Global>,
+}
+
+impl ObjModule {
+    pub fn new(name: ObjRef, script: Option<ObjRef>, file: Option<PathBuf>) -> Self {
+        Self {
+            name,
+            script,
+            file,
+            globals: HashMap::new(),
+        }
+    }
+}
+
+/// Signature of functions implemented in Rust. An `Err` becomes a runtime error.
+pub type NativeFn = fn(&mut Heap, &[Value]) -> Result<Value, String>;
+
+pub struct ObjNative {
+    pub name: &'static str,
+    /// `None` accepts any number of arguments
+    pub arity: Option<usize>,
+    pub function: NativeFn,
+}
+
+/// Allocation size the first collection waits for
+const INITIAL_COLLECTION_THRESHOLD: usize = 1024 * 1024;
+/// After a collection the next one waits until the heap has grown by this factor
+const HEAP_GROWTH_FACTOR: usize = 2;
+
+/// Figures about the heap's memory use
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeapStats {
+    pub live_objects: usize,
+    /// Estimated size of the live objects
+    pub bytes_allocated: usize,
+    pub collections: usize,
+}
+
+/// Storage for every object a program allocates.
+///
+/// Strings are interned: there is only ever one string object with given contents, so
+/// string equality is handle equality.
+///
+/// Objects are reclaimed by a mark and sweep collector. The heap doesn't know what the
+/// program can still reach, so whoever owns the heap allocates through a method that
+/// calls `collect` with its roots, and those of the object being allocated, once
+/// `should_collect` says enough has been allocated. The VM and the compiler do, so any
+/// object they hold only in a Rust variable across an allocation is a bug. With the
+/// `stress_gc` feature every such allocation collects, which finds those bugs.
+pub struct Heap {
+    objects: Vec<Option<Object>>,
+    /// Slots of collected objects that new objects can reuse
+    free: Vec<usize>,
+    strings: HashMap<Rc<str>, ObjRef>,
+    /// Objects that are never collected, such as modules
+    permanent: Vec<ObjRef>,
+    bytes_allocated: usize,
+    next_collection: usize,
+    collections: usize,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            free: Vec::new(),
+            strings: HashMap::new(),
+            permanent: Vec::new(),
+            bytes_allocated: 0,
+            next_collection: INITIAL_COLLECTION_THRESHOLD,
+            collections: 0,
+        }
+    }
+
+    pub fn alloc(&mut self, object: Object) -> ObjRef {
+        self.bytes_allocated += object.size();
+        match self.free.pop() {
+            Some(index) => {
+                self.objects[index] = Some(object);
+                ObjRef::new(index)
+            }
+            None => {
+                self.objects.push(Some(object));
+                ObjRef::new(self.objects.len() - 1)
+            }
+        }
+    }
+
+    /// Keep the object alive for as long as the heap exists
+    pub fn add_permanent_root(&mut self, object: ObjRef) {
+        self.permanent.push(object);
+    }
+
+    /// Whether enough has been allocated since the last collection to run another one
+    pub fn should_collect(&self) -> bool {
+        cfg!(feature = "stress_gc") || self.bytes_allocated > self.next_collection
+    }
+
+    /// Free every object that can't be reached from `roots` or the permanent roots
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = Value>) {
+        let mut marked = vec![false; self.objects.len()];
+        // Marked objects whose references haven't been traced yet
+        let mut gray = Vec::new();
+
+        for root in roots {
+            mark(root, &mut marked, &mut gray);
+        }
+        for root in &self.permanent {
+            mark(Value::Object(*root), &mut marked, &mut gray);
+        }
+        while let Some(object) = gray.pop() {
+            if let Some(object) = self.get(object) {
+                object.trace(|value| mark(value, &mut marked, &mut gray));
+            }
+        }
+
+        // The interned strings table doesn't keep strings alive
+        self.strings.retain(|_, string| marked[string.index()]);
+
+        self.bytes_allocated = 0;
+        for (index, slot) in self.objects.iter_mut().enumerate() {
+            match slot {
+                Some(object) if marked[index] => self.bytes_allocated += object.size(),
+                Some(_) => {
+                    *slot = None;
+                    self.free.push(index);
+                }
+                None => {}
+            }
+        }
+
+        self.next_collection =
+            (self.bytes_allocated * HEAP_GROWTH_FACTOR).max(INITIAL_COLLECTION_THRESHOLD);
+        self.collections += 1;
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            live_objects: self.objects.len() - self.free.len(),
+            bytes_allocated: self.bytes_allocated,
+            collections: self.collections,
+        }
+    }
+
+    pub fn get(&self, object: ObjRef) -> Option<&Object> {
+        match self.objects.get(object.index()) {
+            Some(Some(object)) => Some(object),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, object: ObjRef) -> Option<&mut Object> {
+        match self.objects.get_mut(object.index()) {
+            Some(Some(object)) => Some(object),
+            _ => None,
+        }
+    }
+
+    /// Return the interned string with these contents, allocating it if needed
+    pub fn intern(&mut self, chars: &str) -> ObjRef {
+        if let Some(object) = self.strings.get(chars) {
+            return *object;
+        }
+
+        let chars: Rc<str> = Rc::from(chars);
+        let object = self.alloc(Object::String(ObjString {
+            chars: Rc::clone(&chars),
+        }));
+        self.strings.insert(chars, object);
+        object
+    }
+
+    pub fn get_str(&self, object: ObjRef) -> Option<&str> {
+        match self.get(object) {
+            Some(Object::String(string)) => Some(&string.chars),
+            _ => None,
+        }
+    }
+
+    /// Like `Value::type_name` but looks through object handles
+    pub fn type_name(&self, value: &Value) -> &'static str {
+        match value {
+            Value::Object(object) => match self.get(*object) {
+                Some(object) => object.type_name(),
+                None => "invalid object",
+            },
+            _ => value.type_name(),
+        }
+    }
+
+    /// Wrap a value so it can be formatted with the objects it refers to
+    pub fn display(&self, value: Value) -> ValueDisplay<'_> {
+        ValueDisplay { heap: self, value }
+    }
+}
+
+fn mark(value: Value, marked: &mut [bool], gray: &mut Vec<ObjRef>) {
+    if let Value::Object(object) = value {
+        if let Some(false) = marked.get(object.index()) {
+            marked[object.index()] = true;
+            gray.push(object);
+        }
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct ValueDisplay<'a> {
+    heap: &'a Heap,
+    value: Value,
+}
+
+/// Collections nested deeper than this are elided so cyclic ones can still be printed
+const MAX_DISPLAY_DEPTH: usize = 8;
+
+impl fmt::Display for ValueDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, self.value, 0)
+    }
+}
+
+impl ValueDisplay<'_> {
+    fn write(&self, f: &mut fmt::Formatter<'_>, value: Value, depth: usize) -> fmt::Result {
+        let object = match value {
+            Value::Object(object) => object,
+            value => return write!(f, "{}", value),
+        };
+
+        match self.heap.get(object) {
+            Some(Object::String(string)) => write!(f, "{}", string.chars),
+            Some(Object::Range(range)) => match range.inclusive {
+                true => write!(f, "{}..={}", range.start, range.end),
+                false => write!(f, "{}..{}", range.start, range.end),
+            },
+            Some(Object::List(list)) => {
+                if depth == MAX_DISPLAY_DEPTH {
+                    return write!(f, "[...]");
+                }
+                write!(f, "[")?;
+                for (i, item) in list.items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    self.write(f, *item, depth + 1)?;
+                }
+                write!(f, "]")
+            }
+            Some(Object::Map(map)) => {
+                if depth == MAX_DISPLAY_DEPTH {
+                    return write!(f, "{{...}}");
+                }
+                write!(f, "{{")?;
+                for (i, (key, value)) in map.entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    self.write(f, *key, depth + 1)?;
+                    write!(f, ": ")?;
+                    self.write(f, *value, depth + 1)?;
+                }
+                write!(f, "}}")
+            }
+            Some(Object::Iterator(_)) => write!(f, "<iterator>"),
+            Some(Object::Function(function)) => match function.name {
+                Some(name) => write!(f, "<function {}>", self.heap.display(Value::Object(name))),
+                None => write!(f, "<script>"),
+            },
+            Some(Object::Native(native)) => write!(f, "<native function {}>", native.name),
+            Some(Object::Closure(closure)) => self.write(f, Value::Object(closure.function), depth),
+            Some(Object::Upvalue(_)) => write!(f, "<upvalue>"),
+            Some(Object::Struct(structure)) => write!(
+                f,
+                "<struct {}>",
+                self.heap.display(Value::Object(structure.name))
+            ),
+            Some(Object::Instance(instance)) => {
+                let structure = match self.heap.get(instance.structure) {
+                    Some(Object::Struct(structure)) => structure,
+                    _ => return write!(f, "<instance>"),
+                };
+                write!(f, "{}", self.heap.display(Value::Object(structure.name)))?;
+                if depth == MAX_DISPLAY_DEPTH {
+                    return write!(f, " {{...}}");
+                }
+                write!(f, " {{")?;
+                for (i, (field, value)) in structure.fields.iter().zip(&instance.fields).enumerate()
+                {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, " {}: ", self.heap.display(Value::Object(field.name)))?;
+                    self.write(f, *value, depth + 1)?;
+                }
+                write!(f, " }}")
+            }
+            Some(Object::BoundMethod(bound)) => self.write(f, Value::Object(bound.method), depth),
+            Some(Object::Module(module)) => write!(
+                f,
+                "<module {}>",
+                self.heap.display(Value::Object(module.name))
+            ),
+            None => write!(f, "<invalid object {}>", object.index()),
+        }
+    }
+}
@@ -0,0 +1,566 @@
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::{
+    ByteCode, Chunk, Heap, ObjRef, Object, OpCode, SourceSpan, Value, MAX_LONG, MAX_SHORT,
+};
+
+#[cfg(feature = "debug_print_code")]
+use crate::disassemble_optimized;
+
+/// How much work the optimizer puts into compiled code
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptimizationLevel {
+    /// Run the code as the compiler wrote it
+    #[default]
+    None = 0,
+    /// Fold constant expressions, remove unreachable code and jumps to the next
+    /// instruction, and drop unused and duplicate constants
+    Basic = 1,
+    /// Also rewrite code in ways that can skip runtime errors, like removing
+    /// `Negate` pairs without checking that the operand is a number
+    Aggressive = 2,
+}
+
+impl TryFrom<u8> for OptimizationLevel {
+    type Error = ();
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(OptimizationLevel::None),
+            1 => Ok(OptimizationLevel::Basic),
+            2 => Ok(OptimizationLevel::Aggressive),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Optimize a compiled function and every function and module it refers to
+pub fn optimize(heap: &mut Heap, function: ObjRef, level: OptimizationLevel) {
+    if level == OptimizationLevel::None {
+        return;
+    }
+    let mut visited = HashSet::from([function]);
+    let mut pending = vec![function];
+    while let Some(function) = pending.pop() {
+        let bytecode = match heap.get(function) {
+            Some(Object::Function(function)) => Rc::clone(&function.bytecode),
+            _ => continue,
+        };
+
+        for constant in bytecode.get_constants() {
+            let nested = match constant {
+                Value::Object(object) => match heap.get(*object) {
+                    Some(Object::Function(_)) => Some(*object),
+                    Some(Object::Module(module)) => module.script,
+                    _ => None,
+                },
+                _ => None,
+            };
+            if let Some(nested) = nested {
+                if visited.insert(nested) {
+                    pending.push(nested);
+                }
+            }
+        }
+
+        let optimized = match optimize_bytecode(&bytecode, heap, level) {
+            Some(optimized) => optimized,
+            None => continue,
+        };
+
+        #[cfg(feature = "debug_print_code")]
+        {
+            let name = match heap.get(function) {
+                Some(Object::Function(function)) => function.name,
+                _ => None,
+            };
+            let name = name
+                .and_then(|name| heap.get_str(name))
+                .unwrap_or("<script>");
+            disassemble_optimized(&bytecode, &optimized, heap, name);
+        }
+
+        if let Some(Object::Function(function)) = heap.get_mut(function) {
+            function.bytecode = Rc::new(optimized);
+        }
+    }
+}
+
+/// A decoded instruction, with its constant and jump operands kept apart so they can
+/// be renumbered when the code around them changes
+struct Instruction {
+    /// `Constant` also stands for `ConstantLong`, which one is decided when encoding
+    opcode: OpCode,
+    /// Index into the constant pool being built
+    constant: Option<usize>,
+    /// Index of the instruction jumped to
+    target: Option<usize>,
+    /// Operands after the constant index, if any
+    operands: Vec<Chunk>,
+    line: usize,
+    span: Option<SourceSpan>,
+}
+
+impl Instruction {
+    fn new(opcode: OpCode, line: usize, span: Option<SourceSpan>) -> Self {
+        Self {
+            opcode,
+            constant: None,
+            target: None,
+            operands: Vec::new(),
+            line,
+            span,
+        }
+    }
+
+    /// Whether execution never continues with the next instruction
+    fn ends_block(&self) -> bool {
+        matches!(
+            self.opcode,
+            OpCode::Jump | OpCode::Loop | OpCode::Return | OpCode::NoMatch
+        )
+    }
+}
+
+/// Constants as they are compared when deduplicating, which keeps `0` and `-0` apart
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Nil,
+    Bool(bool),
+    Number(u64),
+    Object(ObjRef),
+}
+
+impl From<Value> for ConstantKey {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Nil => ConstantKey::Nil,
+            Value::Bool(value) => ConstantKey::Bool(value),
+            Value::Number(value) => ConstantKey::Number(value.to_bits()),
+            Value::Object(object) => ConstantKey::Object(object),
+        }
+    }
+}
+
+/// The optimized version of `bytecode`, or `None` when it can't be decoded or its
+/// rewrite doesn't fit the instruction encoding
+fn optimize_bytecode(
+    bytecode: &ByteCode,
+    heap: &mut Heap,
+    level: OptimizationLevel,
+) -> Option<ByteCode> {
+    let mut instructions = decode(bytecode, heap)?;
+    let mut constants = bytecode.get_constants().to_vec();
+    loop {
+        let mut changed = fold_constants(&mut instructions, &mut constants, heap);
+        if level >= OptimizationLevel::Aggressive {
+            changed |= remove_negate_pairs(&mut instructions);
+        }
+        changed |= remove_dead_code(&mut instructions);
+        if !changed {
+            break;
+        }
+    }
+    encode(&instructions, &constants)
+}
+
+fn decode(bytecode: &ByteCode, heap: &Heap) -> Option<Vec<Instruction>> {
+    let mut instructions = Vec::new();
+    // Jumps by the offset of the instruction they jump to
+    let mut jumps = Vec::new();
+    let mut indices = HashMap::new();
+    let mut offset = 0;
+    while offset < bytecode.chunk_count() {
+        let opcode = OpCode::try_from(*bytecode.get_chunk(offset)?).ok()?;
+        let line = bytecode.get_line(offset).copied().unwrap_or(0);
+        let mut instruction = Instruction::new(opcode, line, bytecode.get_span(offset));
+        let length = match opcode {
+            OpCode::Constant => {
+                instruction.constant = Some(usize::from(*bytecode.get_chunk(offset + 1)?));
+                2
+            }
+            OpCode::ConstantLong => {
+                instruction.opcode = OpCode::Constant;
+                instruction.constant = Some(bytecode.get_long(offset + 1)?);
+                4
+            }
+            OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::ForIter
+            | OpCode::LessJumpIfFalse
+            | OpCode::GreaterJumpIfFalse
+            | OpCode::EqualJumpIfFalse => {
+                jumps.push((
+                    instructions.len(),
+                    offset + 3 + bytecode.get_short(offset + 1)?,
+                ));
+                3
+            }
+            OpCode::Loop => {
+                let target = (offset + 3).checked_sub(bytecode.get_short(offset + 1)?)?;
+                jumps.push((instructions.len(), target));
+                3
+            }
+            OpCode::DefineGlobal
+            | OpCode::DefineMutableGlobal
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::Struct
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::Export
+            | OpCode::Import
+            | OpCode::Field
+            | OpCode::Method
+            | OpCode::Closure
+            | OpCode::AddConstant
+            | OpCode::IncrementLocal => {
+                let index = bytecode.get_long(offset + 1)?;
+                instruction.constant = Some(index);
+                let operands = match opcode {
+                    OpCode::Field | OpCode::Method | OpCode::IncrementLocal => 1,
+                    OpCode::Closure => match bytecode.get_constant(index)? {
+                        Value::Object(function) => match heap.get(*function) {
+                            Some(Object::Function(function)) => function.upvalue_count * 2,
+                            _ => return None,
+                        },
+                        _ => return None,
+                    },
+                    _ => 0,
+                };
+                let operands = bytecode
+                    .get_chunks()
+                    .get(offset + 4..offset + 4 + operands)?;
+                instruction.operands = operands.to_vec();
+                4 + operands.len()
+            }
+            OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::Call
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::InRange => {
+                instruction.operands = vec![*bytecode.get_chunk(offset + 1)?];
+                2
+            }
+            OpCode::BuildList | OpCode::BuildMap => {
+                instruction.operands = bytecode.get_chunks().get(offset + 1..offset + 3)?.to_vec();
+                3
+            }
+            _ => 1,
+        };
+        indices.insert(offset, instructions.len());
+        instructions.push(instruction);
+        offset += length;
+    }
+
+    for (jump, target) in jumps {
+        instructions[jump].target = Some(*indices.get(&target)?);
+    }
+    Some(instructions)
+}
+
+/// Instructions other instructions jump to
+fn jump_targets(instructions: &[Instruction]) -> Vec<bool> {
+    let mut targets = vec![false; instructions.len()];
+    for target in instructions
+        .iter()
+        .filter_map(|instruction| instruction.target)
+    {
+        targets[target] = true;
+    }
+    targets
+}
+
+/// Drop the instructions marked in `removed`, pointing jumps at removed instructions
+/// to the first instruction kept after them
+fn remove(instructions: &mut Vec<Instruction>, removed: &[bool]) {
+    let mut indices = Vec::with_capacity(instructions.len() + 1);
+    let mut kept = 0;
+    for removed in removed {
+        indices.push(kept);
+        if !removed {
+            kept += 1;
+        }
+    }
+    indices.push(kept);
+
+    let mut index = 0;
+    instructions.retain(|_| {
+        index += 1;
+        !removed[index - 1]
+    });
+    for instruction in instructions.iter_mut() {
+        if let Some(target) = instruction.target {
+            instruction.target = Some(indices[target]);
+        }
+    }
+}
+
+/// The value a literal instruction pushes
+fn literal(instruction: &Instruction, constants: &[Value], heap: &Heap) -> Option<Value> {
+    match instruction.opcode {
+        OpCode::Nil => Some(Value::Nil),
+        OpCode::True => Some(Value::Bool(true)),
+        OpCode::False => Some(Value::Bool(false)),
+        OpCode::Constant => literal_constant(constants, instruction.constant?, heap),
+        _ => None,
+    }
+}
+
+/// The constant if the optimizer can compute with it, which excludes functions and
+/// modules
+fn literal_constant(constants: &[Value], index: usize, heap: &Heap) -> Option<Value> {
+    match constants.get(index)? {
+        Value::Object(object) if heap.get_str(*object).is_none() => None,
+        constant => Some(*constant),
+    }
+}
+
+/// What the operator does to literal operands, `None` when it would raise an error
+fn fold(opcode: OpCode, a: Value, b: Value, heap: &mut Heap) -> Option<Value> {
+    if let (OpCode::Add, Value::Object(a), Value::Object(b)) = (opcode, a, b) {
+        let concatenated = [heap.get_str(a)?, heap.get_str(b)?].concat();
+        return Some(Value::Object(heap.intern(&concatenated)));
+    }
+    if opcode == OpCode::Equal {
+        return Some(Value::Bool(a == b));
+    }
+    let (a, b) = match (a, b) {
+        (Value::Number(a), Value::Number(b)) => (a, b),
+        _ => return None,
+    };
+    match opcode {
+        OpCode::Add => Some(Value::Number(a + b)),
+        OpCode::Subtract => Some(Value::Number(a - b)),
+        OpCode::Multiply => Some(Value::Number(a * b)),
+        OpCode::Divide => Some(Value::Number(a / b)),
+        OpCode::Greater => Some(Value::Bool(a > b)),
+        OpCode::Less => Some(Value::Bool(a < b)),
+        _ => None,
+    }
+}
+
+/// Turn the instruction into one pushing `value`
+fn set_literal(instruction: &mut Instruction, value: Value, constants: &mut Vec<Value>) {
+    instruction.constant = None;
+    instruction.opcode = match value {
+        Value::Nil => OpCode::Nil,
+        Value::Bool(true) => OpCode::True,
+        Value::Bool(false) => OpCode::False,
+        _ => {
+            instruction.constant = Some(constants.len());
+            constants.push(value);
+            OpCode::Constant
+        }
+    };
+}
+
+/// Evaluate operators whose operands are literals, so `60 * 60 * 24` pushes a single
+/// constant. Operations that would raise a runtime error are left for the VM.
+fn fold_constants(
+    instructions: &mut Vec<Instruction>,
+    constants: &mut Vec<Value>,
+    heap: &mut Heap,
+) -> bool {
+    let targets = jump_targets(instructions);
+    let mut removed = vec![false; instructions.len()];
+    let mut changed = false;
+    let mut i = 0;
+    while i < instructions.len() {
+        let a = match literal(&instructions[i], constants, heap) {
+            Some(a) => a,
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+
+        // Unary operators
+        if let Some(operator) = instructions.get(i + 1).filter(|_| !targets[i + 1]) {
+            let folded = match (operator.opcode, a) {
+                (OpCode::Negate, Value::Number(a)) => Some(Value::Number(-a)),
+                (OpCode::Not, a) => Some(Value::Bool(a.is_falsey())),
+                _ => None,
+            };
+            if let Some(folded) = folded {
+                set_literal(&mut instructions[i], folded, constants);
+                removed[i + 1] = true;
+                changed = true;
+                i += 2;
+                continue;
+            }
+        }
+
+        // Adding a constant operand
+        if let Some(operator) = instructions.get(i + 1).filter(|_| !targets[i + 1]) {
+            let b = match (operator.opcode, operator.constant) {
+                (OpCode::AddConstant, Some(b)) => literal_constant(constants, b, heap),
+                _ => None,
+            };
+            if let Some(folded) = b.and_then(|b| fold(OpCode::Add, a, b, heap)) {
+                set_literal(&mut instructions[i], folded, constants);
+                removed[i + 1] = true;
+                changed = true;
+                i += 2;
+                continue;
+            }
+        }
+
+        // Binary operators
+        let b = match instructions.get(i + 1).filter(|_| !targets[i + 1]) {
+            Some(b) => literal(b, constants, heap),
+            None => None,
+        };
+        let operator = instructions.get(i + 2).filter(|_| !targets[i + 2]);
+        if let (Some(b), Some(operator)) = (b, operator) {
+            if let Some(folded) = fold(operator.opcode, a, b, heap) {
+                set_literal(&mut instructions[i], folded, constants);
+                removed[i + 1] = true;
+                removed[i + 2] = true;
+                changed = true;
+                i += 3;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    remove(instructions, &removed);
+    changed
+}
+
+/// Remove `Negate` instructions that undo each other
+fn remove_negate_pairs(instructions: &mut Vec<Instruction>) -> bool {
+    let targets = jump_targets(instructions);
+    let mut removed = vec![false; instructions.len()];
+    let mut changed = false;
+    let mut i = 0;
+    while i + 1 < instructions.len() {
+        if instructions[i].opcode == OpCode::Negate
+            && instructions[i + 1].opcode == OpCode::Negate
+            && !targets[i + 1]
+        {
+            removed[i] = true;
+            removed[i + 1] = true;
+            changed = true;
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+    remove(instructions, &removed);
+    changed
+}
+
+/// Remove instructions no path from the start of the code reaches, like those after
+/// a `Return` or an unconditional jump, and jumps to the instruction right after them
+fn remove_dead_code(instructions: &mut Vec<Instruction>) -> bool {
+    let mut reachable = vec![false; instructions.len()];
+    let mut worklist = vec![0];
+    while let Some(i) = worklist.pop() {
+        if i >= instructions.len() || reachable[i] {
+            continue;
+        }
+        reachable[i] = true;
+        if let Some(target) = instructions[i].target {
+            worklist.push(target);
+        }
+        if !instructions[i].ends_block() {
+            worklist.push(i + 1);
+        }
+    }
+
+    let mut removed: Vec<bool> = reachable.iter().map(|reachable| !reachable).collect();
+    for (i, instruction) in instructions.iter().enumerate() {
+        let next = (i + 1..instructions.len()).find(|next| !removed[*next]);
+        if instruction.opcode == OpCode::Jump && instruction.target == next && next.is_some() {
+            removed[i] = true;
+        }
+    }
+    let changed = removed.contains(&true);
+    remove(instructions, &removed);
+    changed
+}
+
+/// Write the instructions back out, keeping only the constants they use and each of
+/// those once
+fn encode(instructions: &[Instruction], constants: &[Value]) -> Option<ByteCode> {
+    let mut bytecode = ByteCode::new();
+    let mut pool = HashMap::new();
+    let mut indices = Vec::with_capacity(instructions.len());
+    for instruction in instructions {
+        let index = match instruction.constant {
+            Some(constant) => {
+                let value = *constants.get(constant)?;
+                let index = *pool
+                    .entry(ConstantKey::from(value))
+                    .or_insert_with(|| bytecode.push_constant(value));
+                Some(index)
+            }
+            None => None,
+        };
+        indices.push(index);
+    }
+    if bytecode.get_constants().len() > MAX_LONG + 1 {
+        return None;
+    }
+
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    let mut offset = 0;
+    for (instruction, index) in instructions.iter().zip(&indices) {
+        offsets.push(offset);
+        offset += match (instruction.opcode, index) {
+            (OpCode::Constant, Some(index)) if *index <= usize::from(u8::MAX) => 2,
+            (_, Some(_)) => 4 + instruction.operands.len(),
+            (_, None) if instruction.target.is_some() => 3,
+            (_, None) => 1 + instruction.operands.len(),
+        };
+    }
+
+    for (i, (instruction, index)) in instructions.iter().zip(&indices).enumerate() {
+        let mut chunks = Vec::new();
+        match (instruction.opcode, index) {
+            (OpCode::Constant, Some(index)) if *index <= usize::from(u8::MAX) => {
+                chunks.extend([OpCode::Constant.into(), *index as Chunk]);
+            }
+            (OpCode::Constant, Some(index)) => {
+                chunks.push(OpCode::ConstantLong.into());
+                chunks.extend(long(*index));
+            }
+            (opcode, Some(index)) => {
+                chunks.push(opcode.into());
+                chunks.extend(long(*index));
+            }
+            (opcode, None) => chunks.push(opcode.into()),
+        }
+        if let Some(target) = instruction.target {
+            let after = offsets[i] + 3;
+            let distance = match instruction.opcode {
+                OpCode::Loop => after.checked_sub(offsets[target])?,
+                _ => offsets[target].checked_sub(after)?,
+            };
+            if distance > MAX_SHORT {
+                return None;
+            }
+            chunks.extend([(distance >> 8) as Chunk, distance as Chunk]);
+        }
+        chunks.extend(&instruction.operands);
+
+        for chunk in chunks {
+            match instruction.span {
+                Some(span) => bytecode.push_spanned_chunk(chunk, instruction.line, span),
+                None => bytecode.push_chunk(chunk, instruction.line),
+            }
+        }
+    }
+    Some(bytecode)
+}
+
+/// A three chunk operand, big-endian
+fn long(operand: usize) -> [Chunk; 3] {
+    [
+        (operand >> 16) as Chunk,
+        (operand >> 8) as Chunk,
+        operand as Chunk,
+    ]
+}
@@ -1,9 +1,20 @@
 use std::convert::TryFrom;
+
+use crate::{Diagnostic, Span};
+
+#[derive(Clone)]
 pub struct Scanner<'a> {
     source: &'a str,
     start: usize,
     current: usize,
     line: usize,
+    /// Column of the current character, counted in chars from 1
+    column: usize,
+    /// Line and column of the first character of the token being scanned
+    start_line: usize,
+    start_column: usize,
+    /// Brace depth inside each string interpolation currently being scanned
+    interpolation: Vec<usize>,
 }
 
 impl<'a> Scanner<'a> {
@@ -13,6 +24,10 @@ impl<'a> Scanner<'a> {
             start: 0,
             current: 0,
             line: 1,
+            column: 1,
+            start_line: 1,
+            start_column: 1,
+            interpolation: Vec::new(),
         }
     }
 
@@ -22,38 +37,108 @@ impl<'a> Scanner<'a> {
         } else {
             let c = self.source[self.current..].chars().next().unwrap();
             self.current += c.len_utf8();
+            self.column = match c {
+                '\n' => 1,
+                _ => self.column + 1,
+            };
             Some(c)
         }
     }
 
-    pub fn get_lexeme(&self) -> &str {
+    pub fn get_lexeme(&self) -> &'a str {
         &self.source[self.start..self.current]
     }
 
-    fn string_token(&mut self) -> Result<Token, Token> {
-        while let Some(c) = self.peek() {
-            if c == '"' {
-                break;
-            }
-            if c == '\n' {
-                self.line += 1;
+    /// Scan the rest of a string literal, or the next fragment of an interpolated string.
+    /// A `{` ends the fragment and the embedded expression is scanned as regular tokens
+    /// until the matching `}`. `resumed` is set for the fragments after that `}`.
+    fn string_token(&mut self, resumed: bool) -> Result<Token<'a>, Diagnostic> {
+        let mut error = None;
+        loop {
+            match self.advance() {
+                Some('"') => break,
+                Some('{') => {
+                    // Only whitespace up to the `}` leaves nothing to embed
+                    let brace = self.current - 1;
+                    let rest = &self.source[self.current..];
+                    let inside = rest.len() - rest.trim_start_matches([' ', '\t', '\r']).len();
+                    if rest[inside..].starts_with('}') {
+                        for _ in 0..=inside {
+                            self.advance();
+                        }
+                        let message = "Expect expression in string interpolation.";
+                        error.get_or_insert(self.error_at(brace, message));
+                        continue;
+                    }
+
+                    self.interpolation.push(0);
+                    let typee = match resumed {
+                        true => TokenType::InterpolationMiddle,
+                        false => TokenType::Interpolation,
+                    };
+                    return match error {
+                        Some(error) => Err(error),
+                        None => Ok(self.make_token(typee)),
+                    };
+                }
+                Some('\\') => {
+                    let escape = self.current - 1;
+                    if let Err(message) = self.escape_sequence() {
+                        error.get_or_insert(self.error_at(escape, message));
+                    }
+                }
+                Some('\n') => self.line += 1,
+                Some(_) => {}
+                None => return Err(self.error_at(self.start, "Unterminated string.")),
             }
-            self.advance();
         }
 
-        if self.is_at_end() {
-            return Err(Token::new(
-                "Unterminated string",
-                TokenType::Errorr,
-                self.line,
-            ));
+        let typee = match resumed {
+            true => TokenType::InterpolationEnd,
+            false => TokenType::String,
         };
+        match error {
+            Some(error) => Err(error),
+            None => Ok(self.make_token(typee)),
+        }
+    }
 
-        self.advance();
-        Ok(Token::new(self.get_lexeme(), TokenType::String, self.line))
+    /// Validate the escape sequence following a backslash
+    fn escape_sequence(&mut self) -> Result<(), &'static str> {
+        match self.advance() {
+            Some('n' | 't' | 'r' | '\\' | '"' | '{' | '}' | '0') => Ok(()),
+            Some('u') => {
+                if self.peek() != Some('{') {
+                    return Err("Expect '{' after '\\u'.");
+                }
+                self.advance();
+                let start = self.current;
+                while let Some(c) = self.peek() {
+                    if !c.is_ascii_hexdigit() {
+                        break;
+                    }
+                    self.advance();
+                }
+                let digits = &self.source[start..self.current];
+                if self.peek() != Some('}') {
+                    return Err("Unterminated unicode escape sequence.");
+                }
+                self.advance();
+                match unicode_escape(digits) {
+                    Some(_) => Ok(()),
+                    None => Err("Invalid unicode escape sequence."),
+                }
+            }
+            Some('\n') => {
+                self.line += 1;
+                Err("Unknown escape sequence.")
+            }
+            Some(_) => Err("Unknown escape sequence."),
+            None => Err("Unterminated string."),
+        }
     }
 
-    fn number_token(&mut self) -> Token {
+    fn number_token(&mut self) -> Token<'a> {
         while let Some(c) = self.peek() {
             if c.is_ascii_digit() {
                 self.advance();
@@ -79,7 +164,7 @@ impl<'a> Scanner<'a> {
             }
         }
 
-        Token::new(self.get_lexeme(), TokenType::Number, self.line)
+        self.make_token(TokenType::Number)
     }
 
     fn is_alpha(&self, c: char) -> bool {
@@ -103,12 +188,14 @@ impl<'a> Scanner<'a> {
 
     fn identifier_type(&self) -> TokenType {
         match self.get_lexeme().chars().nth(0).unwrap() {
-            // TODO: figure out how to make "else if" work
             'a' => self.check_keyword(1, 2, "nd", TokenType::And),
+            'b' => self.check_keyword(1, 4, "reak", TokenType::Break),
+            'c' => self.check_keyword(1, 7, "ontinue", TokenType::Continue),
             'e' => self.check_keyword(1, 3, "lse", TokenType::Else),
             'f' => match self.get_lexeme().chars().nth(1) {
                 Some('a') => self.check_keyword(2, 3, "lse", TokenType::False),
                 Some('o') => self.check_keyword(2, 1, "r", TokenType::For),
+                Some('r') => self.check_keyword(2, 2, "om", TokenType::From),
                 Some('u') => self.check_keyword(2, 6, "nction", TokenType::Function),
                 _ => TokenType::Identifier,
             },
@@ -124,7 +211,11 @@ impl<'a> Scanner<'a> {
                 Some('u') => self.check_keyword(2, 5, "table", TokenType::Mutable),
                 _ => TokenType::Identifier,
             },
-            'n' => self.check_keyword(1, 2, "ot", TokenType::Not),
+            'n' => match self.get_lexeme().chars().nth(1) {
+                Some('i') => self.check_keyword(2, 1, "l", TokenType::Nil),
+                Some('o') => self.check_keyword(2, 1, "t", TokenType::Not),
+                _ => TokenType::Identifier,
+            },
             'o' => self.check_keyword(1, 1, "r", TokenType::Or),
             'p' => self.check_keyword(1, 5, "ublic", TokenType::Public),
             't' => self.check_keyword(1, 3, "rue", TokenType::True),
@@ -139,7 +230,7 @@ impl<'a> Scanner<'a> {
         }
     }
 
-    fn identifier_token(&mut self) -> Token {
+    fn identifier_token(&mut self) -> Token<'a> {
         while let Some(c) = self.peek() {
             if c.is_ascii_digit() || self.is_alpha(c) {
                 self.advance();
@@ -147,10 +238,33 @@ impl<'a> Scanner<'a> {
                 break;
             }
         }
-        Token::new(self.get_lexeme(), self.identifier_type(), self.line)
+        let typee = self.identifier_type();
+        if typee == TokenType::Else && self.else_if() {
+            return self.make_token(TokenType::ElseIf);
+        }
+        self.make_token(typee)
     }
 
-    pub fn get_token(&mut self) -> Result<Token, Token> {
+    /// Extend an `else` into `else if` when `if` follows on the same line
+    fn else_if(&mut self) -> bool {
+        let rest = &self.source[self.current..];
+        let keyword = rest.trim_start_matches([' ', '\t']);
+        if keyword.len() == rest.len() || !keyword.starts_with("if") {
+            return false;
+        }
+        if let Some(c) = keyword[2..].chars().next() {
+            if c.is_ascii_digit() || self.is_alpha(c) {
+                return false;
+            }
+        }
+        let skipped = rest.len() - keyword.len() + 2;
+        // Only spaces, tabs and `if` are skipped, so bytes and chars coincide
+        self.current += skipped;
+        self.column += skipped;
+        true
+    }
+
+    pub fn get_token(&mut self) -> Result<Token<'a>, Diagnostic> {
         self.skip_whitespace();
         self.reset_start();
 
@@ -164,32 +278,38 @@ impl<'a> Scanner<'a> {
                 }
 
                 match TokenType::try_from(c) {
-                    Ok(TokenType::LeftParen) => Ok(Token::new(
-                        self.get_lexeme(),
-                        TokenType::LeftParen,
-                        self.line,
-                    )),
-                    Ok(TokenType::RightParen) => Ok(Token::new(
-                        self.get_lexeme(),
-                        TokenType::RightParen,
-                        self.line,
-                    )),
-                    Ok(TokenType::LeftBrace) => Ok(Token::new(
-                        self.get_lexeme(),
-                        TokenType::LeftBrace,
-                        self.line,
-                    )),
-                    Ok(TokenType::RightBrace) => Ok(Token::new(
-                        self.get_lexeme(),
-                        TokenType::RightBrace,
-                        self.line,
-                    )),
-                    Ok(TokenType::Comma) => {
-                        Ok(Token::new(self.get_lexeme(), TokenType::Comma, self.line))
+                    Ok(TokenType::LeftParen) => Ok(self.make_token(TokenType::LeftParen)),
+                    Ok(TokenType::RightParen) => Ok(self.make_token(TokenType::RightParen)),
+                    Ok(TokenType::LeftBrace) => {
+                        if let Some(depth) = self.interpolation.last_mut() {
+                            *depth += 1;
+                        }
+                        Ok(self.make_token(TokenType::LeftBrace))
                     }
+                    Ok(TokenType::RightBrace) => match self.interpolation.last_mut() {
+                        // This brace closes an embedded expression, resume the string
+                        Some(0) => {
+                            self.interpolation.pop();
+                            self.string_token(true)
+                        }
+                        Some(depth) => {
+                            *depth -= 1;
+                            Ok(self.make_token(TokenType::RightBrace))
+                        }
+                        None => Ok(self.make_token(TokenType::RightBrace)),
+                    },
+                    Ok(TokenType::Comma) => Ok(self.make_token(TokenType::Comma)),
                     Ok(TokenType::Dot) => {
-                        Ok(Token::new(self.get_lexeme(), TokenType::Dot, self.line))
+                        if self.peek() == Some('.') {
+                            self.advance();
+                            Ok(self.compound_token(TokenType::DotDot, '=', TokenType::DotDotEqual))
+                        } else {
+                            Ok(self.make_token(TokenType::Dot))
+                        }
                     }
+                    Ok(TokenType::LeftBracket) => Ok(self.make_token(TokenType::LeftBracket)),
+                    Ok(TokenType::RightBracket) => Ok(self.make_token(TokenType::RightBracket)),
+                    Ok(TokenType::Colon) => Ok(self.make_token(TokenType::Colon)),
                     Ok(TokenType::Minus) => {
                         Ok(self.compound_token(TokenType::Minus, '=', TokenType::MinusEqual))
                     }
@@ -205,26 +325,25 @@ impl<'a> Scanner<'a> {
                     Ok(TokenType::Bang) => {
                         Ok(self.compound_token(TokenType::Bang, '=', TokenType::BangEqual))
                     }
-                    Ok(TokenType::Equal) => {
-                        Ok(self.compound_token(TokenType::Equal, '=', TokenType::EqualEqual))
-                    }
+                    Ok(TokenType::Equal) => match self.peek() {
+                        Some('>') => {
+                            self.advance();
+                            Ok(self.make_token(TokenType::FatArrow))
+                        }
+                        _ => Ok(self.compound_token(TokenType::Equal, '=', TokenType::EqualEqual)),
+                    },
                     Ok(TokenType::Less) => {
                         Ok(self.compound_token(TokenType::Less, '=', TokenType::LessEqual))
                     }
                     Ok(TokenType::Greater) => {
                         Ok(self.compound_token(TokenType::Greater, '=', TokenType::GreaterEqual))
                     }
-                    Ok(TokenType::DoubleQuote) => self.string_token(),
+                    Ok(TokenType::DoubleQuote) => self.string_token(false),
                     Ok(_) => panic!("Unexpected token - should be unreachable"),
-                    // TODO: make this a real error type to clean this up
-                    Err(_) => Err(Token::new(
-                        "Unexpected character",
-                        TokenType::Errorr,
-                        self.line,
-                    )),
+                    Err(_) => Err(self.error_at(self.start, "Unexpected character.")),
                 }
             }
-            None => Ok(Token::new(self.get_lexeme(), TokenType::EOF, self.line)),
+            None => Ok(self.make_token(TokenType::EOF)),
         }
     }
 
@@ -233,12 +352,12 @@ impl<'a> Scanner<'a> {
         token_type: TokenType,
         expected: char,
         matched_type: TokenType,
-    ) -> Token {
+    ) -> Token<'a> {
         if self.peek() == Some(expected) {
             self.advance().unwrap();
-            Token::new(self.get_lexeme(), matched_type, self.line)
+            self.make_token(matched_type)
         } else {
-            Token::new(self.get_lexeme(), token_type, self.line)
+            self.make_token(token_type)
         }
     }
 
@@ -262,34 +381,47 @@ impl<'a> Scanner<'a> {
         }
     }
 
+    fn make_token(&self, typee: TokenType) -> Token<'a> {
+        Token::new(
+            self.get_lexeme(),
+            typee,
+            self.start_line,
+            self.start_column,
+            self.start,
+        )
+    }
+
+    /// An error about the source from `start` to the current character
+    fn error_at(&self, start: usize, message: &str) -> Diagnostic {
+        Diagnostic::error(message, self.line).with_span(self.source, Span::new(start, self.current))
+    }
+
     pub fn reset_start(&mut self) {
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column;
     }
 
     fn skip_whitespace(&mut self) {
-        loop {
-            if let Some(c) = self.peek() {
-                match c {
-                    ' ' | '\r' | '\t' => {
-                        self.advance();
-                        break;
-                    }
-                    '\n' => {
-                        self.line += 1;
-                        self.advance();
-                        break;
-                    }
-                    '#' => {
-                        // A comment goes until the end of the line
-                        while let Some(c) = self.peek() {
-                            if c == '\n' {
-                                break;
-                            }
-                            self.advance();
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\r' | '\t' => {
+                    self.advance();
+                }
+                '\n' => {
+                    self.line += 1;
+                    self.advance();
+                }
+                '#' => {
+                    // A comment goes until the end of the line
+                    while let Some(c) = self.peek() {
+                        if c == '\n' {
+                            break;
                         }
+                        self.advance();
                     }
-                    _ => break,
                 }
+                _ => break,
             }
         }
     }
@@ -302,10 +434,15 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
+    Colon,
     Dot,
     DoubleQuote,
     // One of two character tokens
+    DotDot,
+    DotDotEqual,
     Minus,
     MinusEqual,
     Plus,
@@ -318,6 +455,7 @@ pub enum TokenType {
     BangEqual,
     Equal,
     EqualEqual,
+    FatArrow,
     Greater,
     GreaterEqual,
     Less,
@@ -325,13 +463,22 @@ pub enum TokenType {
     // Literals
     Identifier,
     String,
+    /// A string fragment that is followed by an embedded expression
+    Interpolation,
+    /// A string fragment between two embedded expressions
+    InterpolationMiddle,
+    /// The string fragment after the last embedded expression
+    InterpolationEnd,
     Number,
     // Keywords
     And,
+    Break,
+    Continue,
     Else,
     ElseIf,
     False,
     For,
+    From,
     Function,
     If,
     Import,
@@ -339,6 +486,7 @@ pub enum TokenType {
     Let,
     Match,
     Mutable,
+    Nil,
     Not,
     Or,
     Public,
@@ -351,7 +499,6 @@ pub enum TokenType {
 
     // Other
     EOF,
-    Errorr,
 }
 
 impl TryFrom<char> for TokenType {
@@ -365,6 +512,9 @@ impl TryFrom<char> for TokenType {
             '}' => Ok(TokenType::RightBrace),
             ',' => Ok(TokenType::Comma),
             '.' => Ok(TokenType::Dot),
+            '[' => Ok(TokenType::LeftBracket),
+            ']' => Ok(TokenType::RightBracket),
+            ':' => Ok(TokenType::Colon),
             '-' => Ok(TokenType::Minus),
             '+' => Ok(TokenType::Plus),
             '/' => Ok(TokenType::Slash),
@@ -379,18 +529,71 @@ impl TryFrom<char> for TokenType {
     }
 }
 
+/// Decode the contents of a string literal, with the surrounding delimiters removed.
+/// The scanner has already rejected malformed escape sequences.
+pub fn unescape(raw: &str) -> String {
+    let mut unescaped = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some('0') => unescaped.push('\0'),
+            Some('u') => {
+                let digits: String = chars.by_ref().skip(1).take_while(|c| *c != '}').collect();
+                if let Some(c) = unicode_escape(&digits) {
+                    unescaped.push(c);
+                }
+            }
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+fn unicode_escape(digits: &str) -> Option<char> {
+    if digits.is_empty() || digits.len() > 6 {
+        return None;
+    }
+    u32::from_str_radix(digits, 16)
+        .ok()
+        .and_then(char::from_u32)
+}
+
+#[derive(Clone, Copy, Debug)]
 pub struct Token<'a> {
     pub lexeme: &'a str,
     pub typee: TokenType,
+    /// Line and column the lexeme starts at, with the column counted in chars from 1
     pub line: usize,
+    pub column: usize,
+    /// Byte offset of the lexeme in the source
+    pub offset: usize,
+    /// Length of the lexeme in bytes
+    pub length: usize,
 }
 
 impl<'a> Token<'a> {
-    pub fn new(lexeme: &'a str, typee: TokenType, line: usize) -> Token<'a> {
+    pub fn new(
+        lexeme: &'a str,
+        typee: TokenType,
+        line: usize,
+        column: usize,
+        offset: usize,
+    ) -> Token<'a> {
         Token {
             lexeme,
             typee,
             line,
+            column,
+            offset,
+            length: lexeme.len(),
         }
     }
 }
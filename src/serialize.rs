@@ -0,0 +1,444 @@
+//! The `.rvc` file format for compiled programs.
+//!
+//! Integers are little-endian and strings are a `u32` byte length followed by UTF-8.
+//! A file is the magic bytes, the format version, the optional path of the source it
+//! was compiled from and the prototype of the script function. A function prototype
+//! is its optional name, arity, upvalue count, instruction stream, line table, span
+//! table and constant pool, with nested functions stored inline. A module is stored in
+//! full where it is first referenced and by index after that, so every import of it
+//! loads the same module.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::{ByteCode, Chunk, Heap, ObjFunction, ObjModule, ObjRef, Object, SourceSpan, Value};
+
+/// First bytes of every compiled file
+pub const MAGIC: &[u8; 4] = b"RVC\0";
+/// Bumped whenever the layout or the meaning of the instructions changes
+pub const FORMAT_VERSION: u16 = 2;
+/// Conventional extension of compiled files
+pub const BYTECODE_EXTENSION: &str = "rvc";
+
+/// Deepest nesting of functions and modules a file may have, so malformed files can't
+/// exhaust the stack while loading
+const MAX_NESTING: usize = 256;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+const TAG_MODULE: u8 = 6;
+
+/// Why a compiled file couldn't be loaded
+#[derive(Debug, PartialEq, Eq)]
+pub struct LoadError {
+    pub message: String,
+    /// Byte of the file the problem was found at
+    pub offset: usize,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid bytecode file at byte {}: {}",
+            self.offset, self.message
+        )
+    }
+}
+
+/// A compiled program loaded into a heap
+pub struct LoadedProgram {
+    pub script: ObjRef,
+    /// Source file the program was compiled from, if it was compiled from one
+    pub source: Option<PathBuf>,
+}
+
+/// Whether the bytes start like a compiled file rather than source code
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Write the script function compiled from the file at `source`, with every function
+/// and module it refers to
+pub fn serialize(
+    heap: &Heap,
+    script: ObjRef,
+    source: Option<&std::path::Path>,
+) -> Result<Vec<u8>, String> {
+    let mut writer = Writer {
+        heap,
+        bytes: Vec::new(),
+        modules: Vec::new(),
+    };
+    writer.bytes.extend_from_slice(MAGIC);
+    writer.u16(FORMAT_VERSION);
+    match source {
+        Some(source) => {
+            writer.u8(1);
+            writer.string(&source.to_string_lossy())?;
+        }
+        None => writer.u8(0),
+    }
+    writer.function(script)?;
+    Ok(writer.bytes)
+}
+
+/// Load a compiled file into the heap, rejecting files that are truncated or malformed
+pub fn deserialize(bytes: &[u8], heap: &mut Heap) -> Result<LoadedProgram, LoadError> {
+    let mut reader = Reader {
+        bytes,
+        offset: 0,
+        heap,
+        modules: Vec::new(),
+        depth: 0,
+    };
+    if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(LoadError {
+            message: "Not a Raven bytecode file.".to_string(),
+            offset: 0,
+        });
+    }
+    let version = reader.u16()?;
+    if version != FORMAT_VERSION {
+        return Err(reader.error(&format!(
+            "Unsupported format version {}, expected {}.",
+            version, FORMAT_VERSION
+        )));
+    }
+    let source = match reader.flag()? {
+        true => Some(PathBuf::from(reader.string()?)),
+        false => None,
+    };
+    let script = reader.function()?;
+    if reader.offset != bytes.len() {
+        return Err(reader.error("Unexpected data after the script."));
+    }
+    Ok(LoadedProgram { script, source })
+}
+
+struct Writer<'a> {
+    heap: &'a Heap,
+    bytes: Vec<u8>,
+    /// Modules written so far, in the order of their indices
+    modules: Vec<ObjRef>,
+}
+
+impl Writer<'_> {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: usize) -> Result<(), String> {
+        match u32::try_from(value) {
+            Ok(value) => {
+                self.bytes.extend_from_slice(&value.to_le_bytes());
+                Ok(())
+            }
+            Err(_) => Err(format!("{} is too large for a bytecode file.", value)),
+        }
+    }
+
+    fn string(&mut self, string: &str) -> Result<(), String> {
+        self.u32(string.len())?;
+        self.bytes.extend_from_slice(string.as_bytes());
+        Ok(())
+    }
+
+    fn function(&mut self, function: ObjRef) -> Result<(), String> {
+        let function = match self.heap.get(function) {
+            Some(Object::Function(function)) => function,
+            _ => return Err("Expected a function.".to_string()),
+        };
+        match function.name.and_then(|name| self.heap.get_str(name)) {
+            Some(name) => {
+                self.u8(1);
+                self.string(name)?;
+            }
+            None => self.u8(0),
+        }
+        self.u32(function.arity)?;
+        self.u32(function.upvalue_count)?;
+
+        let bytecode = &function.bytecode;
+        self.u32(bytecode.chunk_count())?;
+        self.bytes.extend_from_slice(bytecode.get_chunks());
+        self.u32(bytecode.get_line_runs().len())?;
+        for (start, line) in bytecode.get_line_runs() {
+            self.u32(*start)?;
+            self.u32(*line)?;
+        }
+        self.u32(bytecode.get_span_runs().len())?;
+        for (start, span) in bytecode.get_span_runs() {
+            self.u32(*start)?;
+            self.u32(span.offset as usize)?;
+            self.u32(span.length as usize)?;
+            self.u32(span.column as usize)?;
+        }
+        self.u32(bytecode.get_constants().len())?;
+        for constant in bytecode.get_constants() {
+            self.constant(*constant)?;
+        }
+        Ok(())
+    }
+
+    fn constant(&mut self, constant: Value) -> Result<(), String> {
+        match constant {
+            Value::Nil => self.u8(TAG_NIL),
+            Value::Bool(false) => self.u8(TAG_FALSE),
+            Value::Bool(true) => self.u8(TAG_TRUE),
+            Value::Number(number) => {
+                self.u8(TAG_NUMBER);
+                self.bytes.extend_from_slice(&number.to_le_bytes());
+            }
+            Value::Object(object) => match self.heap.get(object) {
+                Some(Object::String(string)) => {
+                    self.u8(TAG_STRING);
+                    self.string(&string.chars)?;
+                }
+                Some(Object::Function(_)) => {
+                    self.u8(TAG_FUNCTION);
+                    self.function(object)?;
+                }
+                Some(Object::Module(module)) => {
+                    self.u8(TAG_MODULE);
+                    if let Some(index) = self.modules.iter().position(|m| *m == object) {
+                        return self.u32(index);
+                    }
+                    self.u32(self.modules.len())?;
+                    self.modules.push(object);
+
+                    let name = self.heap.get_str(module.name).unwrap_or_default();
+                    self.string(name)?;
+                    match &module.file {
+                        Some(file) => {
+                            self.u8(1);
+                            self.string(&file.to_string_lossy())?;
+                        }
+                        None => self.u8(0),
+                    }
+                    match module.script {
+                        Some(script) => self.function(script)?,
+                        None => return Err(format!("Module '{}' has already run.", name)),
+                    }
+                }
+                _ => {
+                    return Err(format!(
+                        "Can't store a {} constant.",
+                        self.heap.type_name(&constant)
+                    ))
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    heap: &'a mut Heap,
+    /// Modules loaded so far, in the order of their indices
+    modules: Vec<ObjRef>,
+    /// Functions and modules being loaded
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, message: &str) -> LoadError {
+        LoadError {
+            message: message.to_string(),
+            offset: self.offset,
+        }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], LoadError> {
+        let bytes = self.bytes;
+        match self
+            .offset
+            .checked_add(count)
+            .and_then(|end| bytes.get(self.offset..end))
+        {
+            Some(taken) => {
+                self.offset += count;
+                Ok(taken)
+            }
+            None => Err(self.error("Unexpected end of file.")),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn flag(&mut self) -> Result<bool, LoadError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            flag => Err(self.error(&format!("Invalid flag {}.", flag))),
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16, LoadError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<usize, LoadError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn f64(&mut self) -> Result<f64, LoadError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(f64::from_le_bytes(bytes))
+    }
+
+    fn string(&mut self) -> Result<&'a str, LoadError> {
+        let length = self.u32()?;
+        let start = self.offset;
+        match std::str::from_utf8(self.take(length)?) {
+            Ok(string) => Ok(string),
+            Err(_) => Err(LoadError {
+                message: "Invalid UTF-8 in string.".to_string(),
+                offset: start,
+            }),
+        }
+    }
+
+    /// Start runs of a line or span table, which must increase and lie in the
+    /// instruction stream
+    fn run_start(
+        &mut self,
+        previous: Option<usize>,
+        chunk_count: usize,
+    ) -> Result<usize, LoadError> {
+        let start = self.u32()?;
+        if start >= chunk_count || previous.is_some_and(|previous| start <= previous) {
+            return Err(self.error(&format!("Invalid table entry for chunk {}.", start)));
+        }
+        Ok(start)
+    }
+
+    fn nested<T>(
+        &mut self,
+        load: impl FnOnce(&mut Self) -> Result<T, LoadError>,
+    ) -> Result<T, LoadError> {
+        if self.depth == MAX_NESTING {
+            return Err(self.error("Functions nested too deeply."));
+        }
+        self.depth += 1;
+        let result = load(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn function(&mut self) -> Result<ObjRef, LoadError> {
+        self.nested(|reader| {
+            let name = match reader.flag()? {
+                true => {
+                    let name = reader.string()?;
+                    Some(reader.heap.intern(name))
+                }
+                false => None,
+            };
+            let arity = reader.u32()?;
+            let upvalue_count = reader.u32()?;
+
+            let chunk_count = reader.u32()?;
+            let chunks: Vec<Chunk> = reader.take(chunk_count)?.to_vec();
+
+            let mut lines = Vec::new();
+            for _ in 0..reader.u32()? {
+                let start = reader.run_start(lines.last().map(|(start, _)| *start), chunk_count)?;
+                lines.push((start, reader.u32()?));
+            }
+
+            let mut spans = Vec::new();
+            for _ in 0..reader.u32()? {
+                let start = reader.run_start(spans.last().map(|(start, _)| *start), chunk_count)?;
+                let span = SourceSpan {
+                    offset: reader.u32()? as u32,
+                    length: reader.u32()? as u32,
+                    column: reader.u32()? as u32,
+                };
+                spans.push((start, span));
+            }
+
+            let mut constants = Vec::new();
+            for _ in 0..reader.u32()? {
+                constants.push(reader.constant()?);
+            }
+
+            let bytecode = ByteCode::from_parts(chunks, constants, lines, spans);
+            Ok(reader.heap.alloc(Object::Function(ObjFunction {
+                name,
+                arity,
+                upvalue_count,
+                bytecode: Rc::new(bytecode),
+            })))
+        })
+    }
+
+    fn constant(&mut self) -> Result<Value, LoadError> {
+        let start = self.offset;
+        let value = match self.u8()? {
+            TAG_NIL => Value::Nil,
+            TAG_FALSE => Value::Bool(false),
+            TAG_TRUE => Value::Bool(true),
+            TAG_NUMBER => Value::Number(self.f64()?),
+            TAG_STRING => {
+                let string = self.string()?;
+                Value::Object(self.heap.intern(string))
+            }
+            TAG_FUNCTION => Value::Object(self.function()?),
+            TAG_MODULE => Value::Object(self.module()?),
+            tag => {
+                return Err(LoadError {
+                    message: format!("Unknown constant type {}.", tag),
+                    offset: start,
+                })
+            }
+        };
+        Ok(value)
+    }
+
+    /// A module index, followed by the module if this is its first reference
+    fn module(&mut self) -> Result<ObjRef, LoadError> {
+        let index = self.u32()?;
+        if let Some(module) = self.modules.get(index) {
+            return Ok(*module);
+        }
+        if index != self.modules.len() {
+            return Err(self.error(&format!("Module {} is used before it is defined.", index)));
+        }
+
+        self.nested(|reader| {
+            let name = reader.string()?;
+            let name = reader.heap.intern(name);
+            let file = match reader.flag()? {
+                true => Some(PathBuf::from(reader.string()?)),
+                false => None,
+            };
+            // Reserve the index before the script refers to other modules
+            let module = reader
+                .heap
+                .alloc(Object::Module(ObjModule::new(name, None, file)));
+            reader.modules.push(module);
+            let script = reader.function()?;
+            if let Some(Object::Module(module)) = reader.heap.get_mut(module) {
+                module.script = Some(script);
+            }
+            Ok(module)
+        })
+    }
+}
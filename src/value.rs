@@ -1,6 +1,56 @@
+use std::fmt;
 use std::ops::Index;
 
-pub type Value = f64;
+use crate::ObjRef;
+
+/// A value is equal only to a value of the same type with the same contents. Objects
+/// are compared by identity.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Object(ObjRef),
+}
+
+impl Value {
+    /// `nil` and `false` are falsey, every other value is truthy
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Number(_) => "number",
+            Value::Object(_) => "object",
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Number(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Number(value) => write!(f, "{}", value),
+            Value::Object(object) => write!(f, "<object {}>", object.index()),
+        }
+    }
+}
 
 pub struct ConstantPool {
     constants: Vec<Value>,
@@ -22,6 +72,10 @@ impl ConstantPool {
     pub fn get(&self, index: usize) -> Option<&Value> {
         self.constants.get(index)
     }
+
+    pub fn values(&self) -> &[Value] {
+        &self.constants
+    }
 }
 
 impl Default for ConstantPool {
@@ -0,0 +1,479 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::{ByteCode, Heap, ObjRef, Object, OpCode, Value};
+
+/// Why bytecode was rejected before running it
+#[derive(Debug, PartialEq, Eq)]
+pub struct VerifyError {
+    pub message: String,
+    /// Function the bytecode belongs to, `None` for the top-level code of a script or
+    /// module
+    pub function: Option<String>,
+    /// Offset of the offending instruction
+    pub offset: usize,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some(function) => write!(f, "Invalid bytecode in {}()", function)?,
+            None => write!(f, "Invalid bytecode in script")?,
+        }
+        write!(f, " at {:04}: {}", self.offset, self.message)
+    }
+}
+
+/// Check that a function and every function and module it refers to can run without
+/// the VM tripping over its bytecode: opcodes and operands decode, constants exist and
+/// have the right type, jumps land on instructions, and every path through the code
+/// has the same stack depth at each instruction and ends in a return
+pub fn verify(heap: &Heap, function: ObjRef) -> Result<(), VerifyError> {
+    let mut verifier = Verifier {
+        heap,
+        verified: HashSet::from([function]),
+        pending: vec![function],
+    };
+    while let Some(function) = verifier.pending.pop() {
+        verifier.function(function)?;
+    }
+    Ok(())
+}
+
+struct Verifier<'a> {
+    heap: &'a Heap,
+    /// Functions already checked or waiting to be, so shared modules are checked once
+    verified: HashSet<ObjRef>,
+    /// Functions referred to by the ones checked so far
+    pending: Vec<ObjRef>,
+}
+
+/// How an instruction changes the stack and where execution goes after it
+struct Effect {
+    /// Values it needs on the stack
+    pops: usize,
+    pushes: usize,
+    /// Whether execution continues with the next instruction
+    falls_through: bool,
+    /// Offset it may jump to, with the values pushed when it does
+    jump: Option<(usize, usize)>,
+}
+
+impl Effect {
+    fn new(pops: usize, pushes: usize) -> Self {
+        Self {
+            pops,
+            pushes,
+            falls_through: true,
+            jump: None,
+        }
+    }
+
+    fn ends(pops: usize) -> Self {
+        Self {
+            falls_through: false,
+            ..Self::new(pops, 0)
+        }
+    }
+}
+
+/// The bytecode of one function being checked
+struct Code<'a> {
+    bytecode: &'a ByteCode,
+    name: Option<String>,
+    upvalue_count: usize,
+}
+
+impl Code<'_> {
+    fn error(&self, offset: usize, message: &str) -> VerifyError {
+        VerifyError {
+            message: message.to_string(),
+            function: self.name.clone(),
+            offset,
+        }
+    }
+
+    fn chunk(&self, index: usize) -> usize {
+        self.bytecode
+            .get_chunk(index)
+            .map_or(0, |chunk| usize::from(*chunk))
+    }
+
+    fn short(&self, index: usize) -> usize {
+        self.bytecode.get_short(index).unwrap_or(0)
+    }
+
+    fn long(&self, index: usize) -> usize {
+        self.bytecode.get_long(index).unwrap_or(0)
+    }
+
+    /// Check the slot holds a value when the stack is `depth` deep
+    fn local(&self, offset: usize, slot: usize, depth: usize) -> Result<(), VerifyError> {
+        match slot < depth {
+            true => Ok(()),
+            false => Err(self.error(
+                offset,
+                &format!("Local slot {} is outside the frame.", slot),
+            )),
+        }
+    }
+
+    fn flag(&self, offset: usize, index: usize) -> Result<(), VerifyError> {
+        match self.chunk(index) {
+            0 | 1 => Ok(()),
+            flag => Err(self.error(offset, &format!("Invalid flag {}.", flag))),
+        }
+    }
+}
+
+impl Verifier<'_> {
+    fn function(&mut self, function: ObjRef) -> Result<(), VerifyError> {
+        let function = match self.heap.get(function) {
+            Some(Object::Function(function)) => function,
+            _ => {
+                return Err(VerifyError {
+                    message: "Expected a function.".to_string(),
+                    function: None,
+                    offset: 0,
+                })
+            }
+        };
+        let code = Code {
+            bytecode: &function.bytecode,
+            name: function
+                .name
+                .and_then(|name| self.heap.get_str(name))
+                .map(str::to_string),
+            upvalue_count: function.upvalue_count,
+        };
+
+        let boundaries = self.boundaries(&code)?;
+        let chunk_count = code.bytecode.chunk_count();
+        // Stack depth at the start of each instruction reached so far, counting the
+        // callee and arguments in the frame's first slots
+        let mut depths: Vec<Option<usize>> = vec![None; chunk_count];
+        let mut worklist = vec![(0, function.arity + 1)];
+        while let Some((offset, depth)) = worklist.pop() {
+            if offset >= chunk_count {
+                return Err(code.error(offset, "Execution runs past the end of the code."));
+            }
+            if !boundaries[offset] {
+                return Err(code.error(offset, "Jump into the middle of an instruction."));
+            }
+            match depths[offset] {
+                Some(known) if known == depth => continue,
+                Some(known) => {
+                    return Err(code.error(
+                        offset,
+                        &format!(
+                            "Stack depth is {} on one path and {} on another.",
+                            known, depth
+                        ),
+                    ))
+                }
+                None => depths[offset] = Some(depth),
+            }
+
+            let effect = self.effect(&code, offset, depth)?;
+            if depth < effect.pops {
+                return Err(code.error(
+                    offset,
+                    &format!("Needs {} values but the stack has {}.", effect.pops, depth),
+                ));
+            }
+            let after = depth - effect.pops;
+            if let Some((target, pushes)) = effect.jump {
+                worklist.push((target, after + pushes));
+            }
+            if effect.falls_through {
+                let length = self.length(&code, offset)?;
+                worklist.push((offset + length, after + effect.pushes));
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode the code from start to end and mark where each instruction starts
+    fn boundaries(&self, code: &Code) -> Result<Vec<bool>, VerifyError> {
+        let chunk_count = code.bytecode.chunk_count();
+        let mut boundaries = vec![false; chunk_count];
+        let mut offset = 0;
+        while offset < chunk_count {
+            boundaries[offset] = true;
+            offset += self.length(code, offset)?;
+        }
+        Ok(boundaries)
+    }
+
+    /// Chunks the instruction at `offset` takes up, checking it isn't cut short
+    fn length(&self, code: &Code, offset: usize) -> Result<usize, VerifyError> {
+        let chunk = code.chunk(offset) as u8;
+        let opcode = match OpCode::try_from(chunk) {
+            Ok(opcode) => opcode,
+            Err(_) => return Err(code.error(offset, &format!("Unknown opcode {}.", chunk))),
+        };
+        let length = match opcode {
+            OpCode::Constant
+            | OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::Call
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::InRange => 2,
+            OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::LessJumpIfFalse
+            | OpCode::GreaterJumpIfFalse
+            | OpCode::EqualJumpIfFalse
+            | OpCode::Loop
+            | OpCode::ForIter
+            | OpCode::BuildList
+            | OpCode::BuildMap => 3,
+            OpCode::ConstantLong
+            | OpCode::AddConstant
+            | OpCode::DefineGlobal
+            | OpCode::DefineMutableGlobal
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::Struct
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::Export
+            | OpCode::Import => 4,
+            OpCode::Field | OpCode::Method | OpCode::IncrementLocal => 5,
+            OpCode::Closure if offset + 4 > code.bytecode.chunk_count() => 4,
+            OpCode::Closure => 4 + self.function_constant(code, offset)?.1 * 2,
+            OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Negate
+            | OpCode::Return
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::Not
+            | OpCode::Pop
+            | OpCode::ToString
+            | OpCode::GetIndex
+            | OpCode::SetIndex
+            | OpCode::Range
+            | OpCode::RangeInclusive
+            | OpCode::GetIter
+            | OpCode::CloseUpvalue
+            | OpCode::InstanceOf
+            | OpCode::NoMatch => 1,
+        };
+        if offset + length > code.bytecode.chunk_count() {
+            return Err(code.error(offset, &format!("Truncated {:?} instruction.", opcode)));
+        }
+        Ok(length)
+    }
+
+    fn effect(&mut self, code: &Code, offset: usize, depth: usize) -> Result<Effect, VerifyError> {
+        let opcode = match OpCode::try_from(code.chunk(offset) as u8) {
+            Ok(opcode) => opcode,
+            Err(_) => return Err(code.error(offset, "Unknown opcode.")),
+        };
+        let effect = match opcode {
+            OpCode::Constant => {
+                self.constant(code, offset, code.chunk(offset + 1))?;
+                Effect::new(0, 1)
+            }
+            OpCode::ConstantLong => {
+                self.constant(code, offset, code.long(offset + 1))?;
+                Effect::new(0, 1)
+            }
+            OpCode::Nil | OpCode::True | OpCode::False => Effect::new(0, 1),
+            OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::GetIndex
+            | OpCode::Range
+            | OpCode::RangeInclusive
+            | OpCode::InstanceOf => Effect::new(2, 1),
+            OpCode::Negate | OpCode::Not | OpCode::ToString | OpCode::GetIter => Effect::new(1, 1),
+            OpCode::Pop | OpCode::CloseUpvalue => Effect::new(1, 0),
+            OpCode::SetIndex => Effect::new(3, 1),
+            OpCode::Return | OpCode::NoMatch => Effect::ends(1),
+            OpCode::Jump => Effect {
+                jump: Some((offset + 3 + code.short(offset + 1), 0)),
+                ..Effect::ends(0)
+            },
+            OpCode::Loop => {
+                let target = match (offset + 3).checked_sub(code.short(offset + 1)) {
+                    Some(target) => target,
+                    None => return Err(code.error(offset, "Loop jumps before the code.")),
+                };
+                Effect {
+                    jump: Some((target, 0)),
+                    ..Effect::ends(0)
+                }
+            }
+            OpCode::JumpIfFalse => Effect {
+                jump: Some((offset + 3 + code.short(offset + 1), 1)),
+                ..Effect::new(1, 1)
+            },
+            // Leave the result of the comparison for the code on either side to pop
+            OpCode::LessJumpIfFalse | OpCode::GreaterJumpIfFalse | OpCode::EqualJumpIfFalse => {
+                Effect {
+                    jump: Some((offset + 3 + code.short(offset + 1), 1)),
+                    ..Effect::new(2, 1)
+                }
+            }
+            OpCode::AddConstant => {
+                self.constant(code, offset, code.long(offset + 1))?;
+                Effect::new(1, 1)
+            }
+            // Pushes the next item, or leaves the iterator alone when jumping
+            OpCode::ForIter => Effect {
+                jump: Some((offset + 3 + code.short(offset + 1), 1)),
+                ..Effect::new(1, 2)
+            },
+            OpCode::DefineGlobal | OpCode::DefineMutableGlobal => {
+                self.name(code, offset)?;
+                Effect::new(1, 0)
+            }
+            OpCode::GetGlobal | OpCode::Struct => {
+                self.name(code, offset)?;
+                Effect::new(0, 1)
+            }
+            OpCode::SetGlobal | OpCode::GetProperty => {
+                self.name(code, offset)?;
+                Effect::new(1, 1)
+            }
+            OpCode::SetProperty => {
+                self.name(code, offset)?;
+                Effect::new(2, 1)
+            }
+            OpCode::Export => {
+                self.name(code, offset)?;
+                Effect::new(0, 0)
+            }
+            OpCode::Field | OpCode::Method => {
+                self.name(code, offset)?;
+                code.flag(offset, offset + 4)?;
+                match opcode {
+                    OpCode::Field => Effect::new(1, 1),
+                    _ => Effect::new(2, 1),
+                }
+            }
+            OpCode::GetLocal | OpCode::SetLocal => {
+                code.local(offset, code.chunk(offset + 1), depth)?;
+                match opcode {
+                    OpCode::GetLocal => Effect::new(0, 1),
+                    _ => Effect::new(1, 1),
+                }
+            }
+            OpCode::IncrementLocal => {
+                self.constant(code, offset, code.long(offset + 1))?;
+                code.local(offset, code.chunk(offset + 4), depth)?;
+                Effect::new(0, 1)
+            }
+            OpCode::GetUpvalue | OpCode::SetUpvalue => {
+                self.upvalue(code, offset, code.chunk(offset + 1))?;
+                match opcode {
+                    OpCode::GetUpvalue => Effect::new(0, 1),
+                    _ => Effect::new(1, 1),
+                }
+            }
+            OpCode::BuildList => Effect::new(code.short(offset + 1), 1),
+            OpCode::BuildMap => Effect::new(code.short(offset + 1) * 2, 1),
+            OpCode::Call => Effect::new(code.chunk(offset + 1) + 1, 1),
+            OpCode::InRange => {
+                code.flag(offset, offset + 1)?;
+                Effect::new(3, 1)
+            }
+            OpCode::Closure => {
+                let (function, upvalue_count) = self.function_constant(code, offset)?;
+                for upvalue in 0..upvalue_count {
+                    let operands = offset + 4 + upvalue * 2;
+                    code.flag(offset, operands)?;
+                    let index = code.chunk(operands + 1);
+                    match code.chunk(operands) {
+                        0 => self.upvalue(code, offset, index)?,
+                        // A function declared in a block captures the slot the closure
+                        // is about to be pushed into, so it can call itself
+                        _ if index > depth => {
+                            return Err(code.error(
+                                offset,
+                                &format!("Captured slot {} is outside the frame.", index),
+                            ))
+                        }
+                        _ => {}
+                    }
+                }
+                self.queue(function);
+                Effect::new(0, 1)
+            }
+            // Pushes the module and the result of its top-level code
+            OpCode::Import => {
+                let index = code.long(offset + 1);
+                match self.constant(code, offset, index)? {
+                    Value::Object(module) => match self.heap.get(module) {
+                        Some(Object::Module(module)) => {
+                            if let Some(script) = module.script {
+                                self.queue(script);
+                            }
+                        }
+                        _ => return Err(code.error(offset, "Import constant must be a module.")),
+                    },
+                    _ => return Err(code.error(offset, "Import constant must be a module.")),
+                }
+                Effect::new(0, 2)
+            }
+        };
+        Ok(effect)
+    }
+
+    fn queue(&mut self, function: ObjRef) {
+        if self.verified.insert(function) {
+            self.pending.push(function);
+        }
+    }
+
+    fn constant(&self, code: &Code, offset: usize, index: usize) -> Result<Value, VerifyError> {
+        match code.bytecode.get_constant(index) {
+            Some(constant) => Ok(*constant),
+            None => Err(code.error(offset, &format!("Constant {} doesn't exist.", index))),
+        }
+    }
+
+    /// Check the three chunk constant operand is a string
+    fn name(&self, code: &Code, offset: usize) -> Result<(), VerifyError> {
+        match self.constant(code, offset, code.long(offset + 1))? {
+            Value::Object(name) if self.heap.get_str(name).is_some() => Ok(()),
+            _ => Err(code.error(offset, "Name constant must be a string.")),
+        }
+    }
+
+    fn upvalue(&self, code: &Code, offset: usize, index: usize) -> Result<(), VerifyError> {
+        match index < code.upvalue_count {
+            true => Ok(()),
+            false => Err(code.error(offset, &format!("Upvalue {} doesn't exist.", index))),
+        }
+    }
+
+    /// The function a `Closure` instruction creates a closure over, with the number of
+    /// variables it captures
+    fn function_constant(
+        &self,
+        code: &Code,
+        offset: usize,
+    ) -> Result<(ObjRef, usize), VerifyError> {
+        let index = code.long(offset + 1);
+        if let Value::Object(function) = self.constant(code, offset, index)? {
+            if let Some(Object::Function(prototype)) = self.heap.get(function) {
+                return Ok((function, prototype.upvalue_count));
+            }
+        }
+        Err(code.error(offset, "Closure constant must be a function."))
+    }
+}
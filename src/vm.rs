@@ -1,11 +1,98 @@
+use std::collections::HashMap;
 use std::fmt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
-use crate::{disassemble_instruction, ByteCode, OpCode, Value, compile};
+use crate::{
+    compile_with_options, deserialize, disassemble_instruction, natives, optimize, verify,
+    ByteCode, Chunk, CompileOptions, Diagnostic, Global, Heap, HeapStats, LoadError, Method,
+    ModuleLoader, ObjBoundMethod, ObjClosure, ObjFunction, ObjInstance, ObjIterator, ObjList,
+    ObjMap, ObjModule, ObjRange, ObjRef, ObjStruct, ObjUpvalue, Object, OpCode, OptimizationLevel,
+    StructMember, Value, VerifyError,
+};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum InterpretError {
-    CompileError,
-    RuntimeError,
+    /// Every diagnostic the compiler found, at least one of them an error
+    CompileError(Vec<Diagnostic>),
+    RuntimeError {
+        diagnostics: Vec<Diagnostic>,
+        /// The calls that were active when the error happened, innermost first
+        trace: Vec<TraceFrame>,
+    },
+    /// A compiled file that couldn't be loaded
+    LoadError(LoadError),
+    /// Bytecode that would trip up the VM, rejected before running any of it
+    VerifyError(VerifyError),
+}
+
+impl InterpretError {
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        match self {
+            InterpretError::CompileError(diagnostics) => diagnostics,
+            InterpretError::RuntimeError { diagnostics, .. } => diagnostics,
+            InterpretError::LoadError(_) | InterpretError::VerifyError(_) => &[],
+        }
+    }
+
+    pub fn trace(&self) -> &[TraceFrame] {
+        match self {
+            InterpretError::CompileError(_)
+            | InterpretError::LoadError(_)
+            | InterpretError::VerifyError(_) => &[],
+            InterpretError::RuntimeError { trace, .. } => trace,
+        }
+    }
+}
+
+impl fmt::Display for InterpretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterpretError::LoadError(error) => return write!(f, "{}", error),
+            InterpretError::VerifyError(error) => return write!(f, "{}", error),
+            _ => {}
+        }
+        for (i, diagnostic) in self.diagnostics().iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+        }
+        for frame in self.trace() {
+            write!(f, "\n{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+/// A call that was active when a runtime error happened
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceFrame {
+    /// `None` for the top-level code of a script or module
+    pub function: Option<String>,
+    pub file: Option<PathBuf>,
+    /// Line of the instruction the call was executing
+    pub line: usize,
+    /// Number of identical calls in a row this frame stands for, more than one when a
+    /// function keeps calling itself from the same line
+    pub count: usize,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some(function) => write!(f, "  in {}()", function)?,
+            None => write!(f, "  in script")?,
+        }
+        match &self.file {
+            Some(file) => write!(f, " at {}:{}", file.display(), self.line)?,
+            None => write!(f, " at line {}", self.line)?,
+        }
+        if self.count > 1 {
+            write!(f, " (repeated {} times)", self.count)?;
+        }
+        Ok(())
+    }
 }
 
 enum BinaryOperation {
@@ -13,41 +100,1486 @@ enum BinaryOperation {
     Subtract,
     Multiply,
     Divide,
+    Greater,
+    Less,
 }
 
-pub struct VirtualMachine {
+/// Default for the most calls that may be active at once
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1024;
+
+/// An active function call
+struct CallFrame {
+    /// Closure being executed, `None` only for the placeholder frame outside any call
+    closure: Option<ObjRef>,
+    /// Module the closure was created in, which holds the globals it uses
+    module: ObjRef,
+    bytecode: Rc<ByteCode>,
     ip: usize,
+    /// Stack index of the frame's slot zero, which holds the called function
+    slots: usize,
+}
+
+impl CallFrame {
+    fn new(closure: Option<ObjRef>, module: ObjRef, bytecode: Rc<ByteCode>, slots: usize) -> Self {
+        Self {
+            closure,
+            module,
+            bytecode,
+            ip: 0,
+            slots,
+        }
+    }
+}
+
+pub struct VirtualMachine {
+    /// The frame of the innermost active call
+    frame: CallFrame,
+    /// Frames of the calls waiting on the current one, outermost first
+    frames: Vec<CallFrame>,
+    max_call_depth: usize,
     stack: Stack<Value>,
+    heap: Heap,
+    /// Module of the main script, its globals persist across calls to `interpret`
+    main: ObjRef,
+    /// Names and values every module starts out with
+    natives: Vec<(ObjRef, Value)>,
+    loader: ModuleLoader,
+    /// Upvalues still pointing at a stack slot, ordered by slot
+    open_upvalues: Vec<ObjRef>,
+    /// Warnings from compiling the last program interpreted
+    warnings: Vec<Diagnostic>,
+    optimization: OptimizationLevel,
+    compile_options: CompileOptions,
+}
+
+/// Line of the instruction the frame is executing
+fn frame_line(frame: &CallFrame) -> usize {
+    // The last chunk read belongs to that instruction
+    match frame.bytecode.get_line(frame.ip.saturating_sub(1)) {
+        Some(line) => *line,
+        None => 0,
+    }
 }
 
 impl VirtualMachine {
     pub fn new() -> Self {
-        Self {
-            ip: 0,
+        Self::with_max_call_depth(DEFAULT_MAX_CALL_DEPTH)
+    }
+
+    /// Create a virtual machine that raises a stack overflow error once more than
+    /// `max_call_depth` calls are active
+    pub fn with_max_call_depth(max_call_depth: usize) -> Self {
+        let mut heap = Heap::new();
+        let natives = natives()
+            .into_iter()
+            .map(|native| {
+                let name = heap.intern(native.name);
+                (name, Value::Object(heap.alloc(Object::Native(native))))
+            })
+            .collect();
+        let name = heap.intern("main");
+        let main = heap.alloc(Object::Module(ObjModule::new(name, None, None)));
+        heap.add_permanent_root(main);
+
+        let mut vm = Self {
+            frame: CallFrame::new(None, main, Rc::new(ByteCode::new()), 0),
+            frames: Vec::new(),
+            max_call_depth,
             stack: Stack::new(),
+            heap,
+            main,
+            natives,
+            loader: ModuleLoader::default(),
+            open_upvalues: Vec::new(),
+            warnings: Vec::new(),
+            optimization: OptimizationLevel::None,
+            compile_options: CompileOptions::default(),
+        };
+        vm.define_natives(main);
+        vm
+    }
+
+    /// Warnings the compiler found in the last program interpreted, if it compiled
+    pub fn take_warnings(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    pub fn heap_stats(&self) -> HeapStats {
+        self.heap.stats()
+    }
+
+    /// Free every object the program can no longer reach
+    pub fn collect_garbage(&mut self) {
+        let roots = self.roots();
+        self.heap.collect(roots);
+    }
+
+    /// Every value the program can still reach directly
+    fn roots(&self) -> Vec<Value> {
+        let mut roots: Vec<Value> = self.stack.iter().copied().collect();
+        for frame in self.frames.iter().chain(std::iter::once(&self.frame)) {
+            if let Some(closure) = frame.closure {
+                roots.push(Value::Object(closure));
+            }
+            roots.push(Value::Object(frame.module));
+        }
+        for upvalue in &self.open_upvalues {
+            roots.push(Value::Object(*upvalue));
         }
+        for (name, native) in &self.natives {
+            roots.push(Value::Object(*name));
+            roots.push(*native);
+        }
+        roots
+    }
+
+    /// Allocate an object, first collecting garbage if enough has been allocated.
+    /// Values the program holds outside the roots must be reachable from `object`.
+    fn alloc(&mut self, object: Object) -> ObjRef {
+        if self.heap.should_collect() {
+            let mut roots = self.roots();
+            object.trace(|value| roots.push(value));
+            self.heap.collect(roots);
+        }
+        self.heap.alloc(object)
+    }
+
+    /// Return the interned string with these contents, first collecting garbage if
+    /// enough has been allocated
+    fn intern(&mut self, chars: &str) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.intern(chars)
+    }
+
+    /// Resolve the imports of the script being interpreted relative to the file at `path`
+    pub fn set_script_path(&mut self, path: &Path) {
+        self.loader.set_script_path(path);
+        let file = self.loader.script_path().map(Path::to_path_buf);
+        if let Some(Object::Module(main)) = self.heap.get_mut(self.main) {
+            main.file = file;
+        }
+    }
+
+    fn define_natives(&mut self, module: ObjRef) {
+        if let Some(Object::Module(module)) = self.heap.get_mut(module) {
+            for (name, value) in &self.natives {
+                let global = Global {
+                    value: *value,
+                    mutable: false,
+                    public: false,
+                };
+                module.globals.insert(*name, global);
+            }
+        }
+    }
+
+    /// How much to optimize the code compiled by `interpret`
+    pub fn set_optimization_level(&mut self, level: OptimizationLevel) {
+        self.optimization = level;
+    }
+
+    /// Choices about the code `interpret` compiles
+    pub fn set_compile_options(&mut self, options: CompileOptions) {
+        self.compile_options = options;
     }
 
     pub fn interpret(&mut self, source: &str) -> Result<(), InterpretError> {
-        compile(source)
+        let script = match compile_with_options(
+            source,
+            &mut self.heap,
+            &mut self.loader,
+            self.compile_options,
+        ) {
+            Ok((script, warnings)) => {
+                self.warnings = warnings;
+                script
+            }
+            Err(diagnostics) => {
+                self.warnings.clear();
+                return Err(InterpretError::CompileError(diagnostics));
+            }
+        };
+        optimize(&mut self.heap, script, self.optimization);
+
+        self.run_script(script)
+    }
+
+    /// Run already compiled bytecode as a script. Malformed bytecode is rejected
+    /// before any of it runs.
+    pub fn execute(&mut self, bytecode: ByteCode) -> Result<(), InterpretError> {
+        let script = self.alloc(Object::Function(ObjFunction {
+            name: None,
+            arity: 0,
+            upvalue_count: 0,
+            bytecode: Rc::new(bytecode),
+        }));
+        self.run_script(script)
+    }
+
+    /// Load a program from the contents of a compiled file and run it
+    pub fn run_bytecode(&mut self, bytes: &[u8]) -> Result<(), InterpretError> {
+        let program = deserialize(bytes, &mut self.heap).map_err(InterpretError::LoadError)?;
+        self.warnings.clear();
+        if let Some(source) = program.source {
+            self.set_script_path(&source);
+        }
+        self.run_script(program.script)
+    }
+
+    fn run_script(&mut self, function: ObjRef) -> Result<(), InterpretError> {
+        verify(&self.heap, function).map_err(InterpretError::VerifyError)?;
+        self.reset();
+        let script = self.alloc(Object::Closure(ObjClosure {
+            function,
+            upvalues: Vec::new(),
+            module: self.main,
+        }));
+        self.stack.push(Value::Object(script));
+        let mut result = self.call_value(Value::Object(script), 0);
+        if result.is_ok() {
+            result = self.run();
+        }
+        // After an error the stack and frames are left as they were when it happened
+        self.reset();
+        result
+    }
+
+    /// Drop every value and call left over from running a script, keeping the globals
+    fn reset(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+        self.frame = CallFrame::new(None, self.main, Rc::new(ByteCode::new()), 0);
+        self.open_upvalues.clear();
+    }
+
+    /// Execute until the function in the current frame returns, leaving its result on
+    /// the stack
+    fn run(&mut self) -> Result<(), InterpretError> {
+        let base = self.frames.len();
+        loop {
+            if cfg!(feature = "debug_trace_execution") {
+                print!("          ");
+                for value in self.stack.iter() {
+                    print!("[ {} ]", self.heap.display(*value));
+                }
+                println!();
+                disassemble_instruction(&self.frame.bytecode, &self.heap, self.frame.ip);
+            };
+
+            let chunk = self.read_chunk()?;
+            let opcode = match OpCode::try_from(chunk) {
+                Ok(opcode) => opcode,
+                Err(_) => return Err(self.runtime_error(&format!("Unknown opcode {}", chunk))),
+            };
+
+            match opcode {
+                OpCode::Constant => {
+                    let index = self.read_operand()?;
+                    let constant = self.constant(index)?;
+                    self.stack.push(constant);
+                }
+                OpCode::ConstantLong => {
+                    let index = self.read_long()?;
+                    let constant = self.constant(index)?;
+                    self.stack.push(constant);
+                }
+                OpCode::Add => self.binary_op(BinaryOperation::Add)?,
+                OpCode::Subtract => self.binary_op(BinaryOperation::Subtract)?,
+                OpCode::Multiply => self.binary_op(BinaryOperation::Multiply)?,
+                OpCode::Divide => self.binary_op(BinaryOperation::Divide)?,
+                OpCode::Negate => match self.pop()? {
+                    Value::Number(value) => self.stack.push(Value::Number(-value)),
+                    value => {
+                        return Err(self.runtime_error(&format!(
+                            "Operand must be a number, got {}.",
+                            self.heap.type_name(&value)
+                        )))
+                    }
+                },
+                OpCode::Nil => self.stack.push(Value::Nil),
+                OpCode::True => self.stack.push(Value::Bool(true)),
+                OpCode::False => self.stack.push(Value::Bool(false)),
+                OpCode::Equal => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    self.stack.push(Value::Bool(a == b));
+                }
+                OpCode::Greater => self.binary_op(BinaryOperation::Greater)?,
+                OpCode::Less => self.binary_op(BinaryOperation::Less)?,
+                OpCode::Not => {
+                    let value = self.pop()?;
+                    self.stack.push(Value::Bool(value.is_falsey()));
+                }
+                OpCode::Pop => {
+                    self.pop()?;
+                }
+                OpCode::ToString => {
+                    let value = self.pop()?;
+                    let string = match value {
+                        Value::Object(object) if self.heap.get_str(object).is_some() => object,
+                        _ => {
+                            let chars = self.heap.display(value).to_string();
+                            self.intern(&chars)
+                        }
+                    };
+                    self.stack.push(Value::Object(string));
+                }
+                OpCode::DefineGlobal | OpCode::DefineMutableGlobal => {
+                    let name = self.read_name()?;
+                    let value = self.pop()?;
+                    let global = Global {
+                        value,
+                        mutable: opcode == OpCode::DefineMutableGlobal,
+                        public: false,
+                    };
+                    match self.globals_mut() {
+                        Some(globals) => globals.insert(name, global),
+                        None => return Err(self.runtime_error("Frame without a module")),
+                    };
+                }
+                OpCode::GetGlobal => {
+                    let name = self.read_name()?;
+                    match self.globals().and_then(|globals| globals.get(&name)) {
+                        Some(global) => self.stack.push(global.value),
+                        None => return Err(self.undefined_variable(name)),
+                    }
+                }
+                OpCode::SetGlobal => {
+                    let name = self.read_name()?;
+                    let value = match self.stack.peek() {
+                        Some(value) => *value,
+                        None => return Err(self.runtime_error("Stack underflow")),
+                    };
+                    match self
+                        .globals_mut()
+                        .and_then(|globals| globals.get_mut(&name))
+                    {
+                        Some(global) if global.mutable => global.value = value,
+                        Some(_) => {
+                            let message = format!(
+                                "Cannot assign to immutable variable '{}'.",
+                                self.heap.get_str(name).unwrap_or_default()
+                            );
+                            return Err(self.runtime_error(&message));
+                        }
+                        None => return Err(self.undefined_variable(name)),
+                    }
+                }
+                OpCode::GetLocal => {
+                    let slot = self.read_operand()?;
+                    match self.stack.get(self.frame.slots + slot) {
+                        Some(value) => self.stack.push(*value),
+                        None => return Err(self.invalid_slot(slot)),
+                    }
+                }
+                OpCode::SetLocal => {
+                    let slot = self.read_operand()?;
+                    let value = match self.stack.peek() {
+                        Some(value) => *value,
+                        None => return Err(self.runtime_error("Stack underflow")),
+                    };
+                    if !self.stack.set(self.frame.slots + slot, value) {
+                        return Err(self.invalid_slot(slot));
+                    }
+                }
+                OpCode::Jump => {
+                    let jump = self.read_short()?;
+                    self.frame.ip += jump;
+                }
+                OpCode::Loop => {
+                    let jump = self.read_short()?;
+                    self.frame.ip = match self.frame.ip.checked_sub(jump) {
+                        Some(ip) => ip,
+                        None => return Err(self.runtime_error("Invalid loop offset")),
+                    };
+                }
+                OpCode::BuildList => {
+                    let count = self.read_short()?;
+                    let items = self.pop_many(count)?;
+                    let list = self.alloc(Object::List(ObjList { items }));
+                    self.stack.push(Value::Object(list));
+                }
+                OpCode::BuildMap => {
+                    let count = self.read_short()?;
+                    let items = self.pop_many(count * 2)?;
+                    let mut map = ObjMap::new();
+                    for entry in items.chunks(2) {
+                        map.insert(entry[0], entry[1]);
+                    }
+                    let map = self.alloc(Object::Map(map));
+                    self.stack.push(Value::Object(map));
+                }
+                OpCode::GetIndex => {
+                    let index = self.pop()?;
+                    let target = self.pop()?;
+                    let value = self.get_index(target, index)?;
+                    self.stack.push(value);
+                }
+                OpCode::SetIndex => {
+                    let value = self.pop()?;
+                    let index = self.pop()?;
+                    let target = self.pop()?;
+                    self.set_index(target, index, value)?;
+                    self.stack.push(value);
+                }
+                OpCode::Range | OpCode::RangeInclusive => {
+                    let end = self.pop()?;
+                    let start = self.pop()?;
+                    let (start, end) = match (start, end) {
+                        (Value::Number(start), Value::Number(end)) => (start, end),
+                        (start, end) => {
+                            let message = format!(
+                                "Range bounds must be numbers, got {} and {}.",
+                                self.heap.type_name(&start),
+                                self.heap.type_name(&end)
+                            );
+                            return Err(self.runtime_error(&message));
+                        }
+                    };
+                    let range = self.alloc(Object::Range(ObjRange {
+                        start,
+                        end,
+                        inclusive: opcode == OpCode::RangeInclusive,
+                    }));
+                    self.stack.push(Value::Object(range));
+                }
+                OpCode::GetIter => {
+                    // The iterable stays rooted while its iterator is allocated
+                    let iterable = self.peek(0)?;
+                    let iterator = self.get_iter(iterable)?;
+                    self.pop()?;
+                    self.stack.push(iterator);
+                }
+                OpCode::ForIter => {
+                    let jump = self.read_short()?;
+                    let iterator = match self.stack.peek() {
+                        Some(Value::Object(iterator)) => *iterator,
+                        _ => return Err(self.runtime_error("Expected an iterator")),
+                    };
+                    match self.iter_next(iterator)? {
+                        Some(item) => self.stack.push(item),
+                        None => self.frame.ip += jump,
+                    }
+                }
+                OpCode::JumpIfFalse => {
+                    let jump = self.read_short()?;
+                    let condition = match self.stack.peek() {
+                        Some(value) => *value,
+                        None => return Err(self.runtime_error("Stack underflow")),
+                    };
+                    if condition.is_falsey() {
+                        self.frame.ip += jump;
+                    }
+                }
+                OpCode::Call => {
+                    let count = self.read_operand()?;
+                    let callee = self.peek(count)?;
+                    self.call_value(callee, count)?;
+                }
+                OpCode::Closure => {
+                    let function = match self.read_constant()? {
+                        Value::Object(function) => function,
+                        _ => return Err(self.runtime_error("Closure constant must be a function")),
+                    };
+                    let upvalue_count = match self.heap.get(function) {
+                        Some(Object::Function(function)) => function.upvalue_count,
+                        _ => return Err(self.runtime_error("Closure constant must be a function")),
+                    };
+
+                    let mut upvalues = Vec::with_capacity(upvalue_count);
+                    for _ in 0..upvalue_count {
+                        let is_local = self.read_operand()?;
+                        let index = self.read_operand()?;
+                        let upvalue = match is_local {
+                            0 => self.frame_upvalue(index)?,
+                            _ => self.capture_upvalue(self.frame.slots + index),
+                        };
+                        upvalues.push(upvalue);
+                    }
+                    let closure = self.alloc(Object::Closure(ObjClosure {
+                        function,
+                        upvalues,
+                        module: self.frame.module,
+                    }));
+                    self.stack.push(Value::Object(closure));
+                }
+                OpCode::GetUpvalue => {
+                    let index = self.read_operand()?;
+                    let upvalue = self.frame_upvalue(index)?;
+                    let value = match self.heap.get(upvalue) {
+                        Some(Object::Upvalue(ObjUpvalue::Open(slot))) => self.stack.get(*slot),
+                        Some(Object::Upvalue(ObjUpvalue::Closed(value))) => Some(value),
+                        _ => None,
+                    };
+                    match value {
+                        Some(value) => self.stack.push(*value),
+                        None => return Err(self.invalid_upvalue(index)),
+                    }
+                }
+                OpCode::SetUpvalue => {
+                    let index = self.read_operand()?;
+                    let value = match self.stack.peek() {
+                        Some(value) => *value,
+                        None => return Err(self.runtime_error("Stack underflow")),
+                    };
+                    let upvalue = self.frame_upvalue(index)?;
+                    let stored = match self.heap.get_mut(upvalue) {
+                        Some(Object::Upvalue(ObjUpvalue::Open(slot))) => {
+                            let slot = *slot;
+                            self.stack.set(slot, value)
+                        }
+                        Some(Object::Upvalue(closed)) => {
+                            *closed = ObjUpvalue::Closed(value);
+                            true
+                        }
+                        _ => false,
+                    };
+                    if !stored {
+                        return Err(self.invalid_upvalue(index));
+                    }
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len().saturating_sub(1));
+                    self.pop()?;
+                }
+                OpCode::Struct => {
+                    let name = self.read_name()?;
+                    let structure = ObjStruct::new(name, self.frame.module);
+                    let structure = self.alloc(Object::Struct(structure));
+                    self.stack.push(Value::Object(structure));
+                }
+                OpCode::Field => {
+                    let name = self.read_name()?;
+                    let public = self.read_operand()? != 0;
+                    match self.peek_struct(0)? {
+                        Some(structure) => structure.fields.push(StructMember { name, public }),
+                        None => return Err(self.runtime_error("Fields belong to a struct")),
+                    }
+                }
+                OpCode::Method => {
+                    let name = self.read_name()?;
+                    let public = self.read_operand()? != 0;
+                    let closure = match self.pop()? {
+                        Value::Object(closure) => closure,
+                        _ => return Err(self.runtime_error("Method must be a closure")),
+                    };
+                    match self.peek_struct(0)? {
+                        Some(structure) => {
+                            structure.methods.insert(name, Method { closure, public });
+                        }
+                        None => return Err(self.runtime_error("Methods belong to a struct")),
+                    }
+                }
+                OpCode::GetProperty => {
+                    let name = self.read_name()?;
+                    let receiver = self.pop()?;
+                    let value = self.get_property(receiver, name)?;
+                    self.stack.push(value);
+                }
+                OpCode::SetProperty => {
+                    let name = self.read_name()?;
+                    let value = self.pop()?;
+                    let receiver = self.pop()?;
+                    self.set_property(receiver, name, value)?;
+                    self.stack.push(value);
+                }
+                OpCode::InstanceOf => {
+                    let structure = self.pop()?;
+                    let value = self.pop()?;
+                    let is_instance = match value {
+                        Value::Object(object) => match self.heap.get(object) {
+                            Some(Object::Instance(instance)) => {
+                                Value::Object(instance.structure) == structure
+                            }
+                            _ => false,
+                        },
+                        _ => false,
+                    };
+                    self.stack.push(Value::Bool(is_instance));
+                }
+                OpCode::InRange => {
+                    let inclusive = self.read_operand()? != 0;
+                    let end = self.pop()?;
+                    let start = self.pop()?;
+                    let value = self.pop()?;
+                    let contained = match (value, start, end) {
+                        (Value::Number(value), Value::Number(start), Value::Number(end)) => {
+                            start <= value && (value < end || inclusive && value == end)
+                        }
+                        _ => false,
+                    };
+                    self.stack.push(Value::Bool(contained));
+                }
+                OpCode::NoMatch => {
+                    let value = self.pop()?;
+                    let message = format!(
+                        "No match arm matches the {} '{}'.",
+                        self.heap.type_name(&value),
+                        self.heap.display(value)
+                    );
+                    return Err(self.runtime_error(&message));
+                }
+                OpCode::Export => {
+                    let name = self.read_name()?;
+                    match self
+                        .globals_mut()
+                        .and_then(|globals| globals.get_mut(&name))
+                    {
+                        Some(global) => global.public = true,
+                        None => return Err(self.undefined_variable(name)),
+                    }
+                }
+                OpCode::Import => {
+                    let module = match self.read_constant()? {
+                        Value::Object(module) => module,
+                        _ => return Err(self.runtime_error("Import constant must be a module")),
+                    };
+                    let script = match self.heap.get_mut(module) {
+                        Some(Object::Module(module)) => module.script.take(),
+                        _ => return Err(self.runtime_error("Import constant must be a module")),
+                    };
+                    self.stack.push(Value::Object(module));
+                    match script {
+                        // The first import runs the module's top-level code to define
+                        // its globals
+                        Some(function) => {
+                            self.define_natives(module);
+                            let script = self.alloc(Object::Closure(ObjClosure {
+                                function,
+                                upvalues: Vec::new(),
+                                module,
+                            }));
+                            self.stack.push(Value::Object(script));
+                            self.call_value(Value::Object(script), 0)?;
+                        }
+                        None => self.stack.push(Value::Nil),
+                    }
+                }
+                OpCode::AddConstant => {
+                    let b = self.read_constant()?;
+                    let a = self.pop()?;
+                    let result = self.binary_result(BinaryOperation::Add, a, b)?;
+                    self.stack.push(result);
+                }
+                OpCode::IncrementLocal => {
+                    let b = self.read_constant()?;
+                    let slot = self.read_operand()?;
+                    let a = match self.stack.get(self.frame.slots + slot) {
+                        Some(value) => *value,
+                        None => return Err(self.invalid_slot(slot)),
+                    };
+                    let result = self.binary_result(BinaryOperation::Add, a, b)?;
+                    self.stack.set(self.frame.slots + slot, result);
+                    self.stack.push(result);
+                }
+                OpCode::LessJumpIfFalse | OpCode::GreaterJumpIfFalse | OpCode::EqualJumpIfFalse => {
+                    let jump = self.read_short()?;
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    let result = match opcode {
+                        OpCode::LessJumpIfFalse => {
+                            self.binary_result(BinaryOperation::Less, a, b)?
+                        }
+                        OpCode::GreaterJumpIfFalse => {
+                            self.binary_result(BinaryOperation::Greater, a, b)?
+                        }
+                        _ => Value::Bool(a == b),
+                    };
+                    self.stack.push(result);
+                    if result.is_falsey() {
+                        self.frame.ip += jump;
+                    }
+                }
+                OpCode::Return => {
+                    let result = self.pop()?;
+                    let slots = self.frame.slots;
+                    self.close_upvalues(slots);
+                    match self.frames.pop() {
+                        Some(caller) => self.frame = caller,
+                        None => return Err(self.runtime_error("Return without a caller")),
+                    }
+                    self.stack.truncate(slots);
+                    self.stack.push(result);
+                    // The call this run started with has finished
+                    if self.frames.len() < base {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    fn call_value(&mut self, callee: Value, count: usize) -> Result<(), InterpretError> {
+        let mut object = match callee {
+            Value::Object(object) => object,
+            _ => return Err(self.not_callable(callee)),
+        };
+
+        match self.heap.get(object) {
+            // The receiver takes the place of the callee so it ends up in slot zero
+            Some(Object::BoundMethod(bound)) => {
+                let receiver = bound.receiver;
+                object = bound.method;
+                let slot = self.stack.len() - count - 1;
+                self.stack.set(slot, receiver);
+            }
+            Some(Object::Struct(structure)) => {
+                if count != structure.fields.len() {
+                    let message = format!(
+                        "Expected {} arguments but got {}.",
+                        structure.fields.len(),
+                        count
+                    );
+                    return Err(self.runtime_error(&message));
+                }
+                let fields = self.pop_many(count)?;
+                self.pop()?;
+                let instance = self.alloc(Object::Instance(ObjInstance {
+                    structure: object,
+                    fields,
+                }));
+                self.stack.push(Value::Object(instance));
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        // Only closures are callable, bare functions are just their prototypes
+        let (callable, module) = match self.heap.get(object) {
+            Some(Object::Closure(closure)) => (self.heap.get(closure.function), closure.module),
+            Some(Object::Function(_)) => (None, self.frame.module),
+            callable => (callable, self.frame.module),
+        };
+        match callable {
+            Some(Object::Function(function)) => {
+                if count != function.arity {
+                    let message =
+                        format!("Expected {} arguments but got {}.", function.arity, count);
+                    return Err(self.runtime_error(&message));
+                }
+                if self.frames.len() + 1 >= self.max_call_depth {
+                    return Err(self.runtime_error("Stack overflow."));
+                }
+
+                let bytecode = Rc::clone(&function.bytecode);
+                let slots = self.stack.len() - count - 1;
+                let frame = CallFrame::new(Some(object), module, bytecode, slots);
+                let caller = std::mem::replace(&mut self.frame, frame);
+                self.frames.push(caller);
+                Ok(())
+            }
+            Some(Object::Native(native)) => {
+                if let Some(arity) = native.arity {
+                    if count != arity {
+                        let message = format!("Expected {} arguments but got {}.", arity, count);
+                        return Err(self.runtime_error(&message));
+                    }
+                }
+
+                let function = native.function;
+                let arguments = self.pop_many(count)?;
+                let result = match function(&mut self.heap, &arguments) {
+                    Ok(result) => result,
+                    Err(message) => return Err(self.runtime_error(&message)),
+                };
+                // Replace the native function itself with its result
+                self.pop()?;
+                self.stack.push(result);
+                Ok(())
+            }
+            _ => Err(self.not_callable(callee)),
+        }
     }
 
-    fn binary_op(&mut self, operation: BinaryOperation) {
-        let b = match self.stack.pop() {
-            Some(element) => element,
-            None => panic!("Stack empty"),
+    /// The struct `distance` values down from the top of the stack
+    fn peek_struct(&mut self, distance: usize) -> Result<Option<&mut ObjStruct>, InterpretError> {
+        let structure = match self.peek(distance)? {
+            Value::Object(structure) => structure,
+            _ => return Ok(None),
         };
-        let a = match self.stack.pop() {
-            Some(element) => element,
-            None => panic!("Stack empty"),
+        match self.heap.get_mut(structure) {
+            Some(Object::Struct(structure)) => Ok(Some(structure)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Globals of the module the code being executed belongs to
+    fn globals(&self) -> Option<&HashMap<ObjRef, Global>> {
+        match self.heap.get(self.frame.module) {
+            Some(Object::Module(module)) => Some(&module.globals),
+            _ => None,
+        }
+    }
+
+    fn globals_mut(&mut self) -> Option<&mut HashMap<ObjRef, Global>> {
+        match self.heap.get_mut(self.frame.module) {
+            Some(Object::Module(module)) => Some(&mut module.globals),
+            _ => None,
+        }
+    }
+
+    /// Read a public global of an imported module
+    fn module_member(&self, module: &ObjModule, name: ObjRef) -> Result<Value, InterpretError> {
+        match module.globals.get(&name) {
+            Some(global) if global.public => Ok(global.value),
+            _ => {
+                let message = format!(
+                    "Module '{}' has no public member '{}'.",
+                    self.heap.get_str(module.name).unwrap_or_default(),
+                    self.heap.get_str(name).unwrap_or_default()
+                );
+                Err(self.runtime_error(&message))
+            }
+        }
+    }
+
+    /// Find the instance and its struct a property is accessed on, checking that the
+    /// member is visible from the code being executed
+    fn property_target(
+        &self,
+        receiver: Value,
+        name: ObjRef,
+    ) -> Result<(ObjRef, &ObjStruct), InterpretError> {
+        let target = match receiver {
+            Value::Object(instance) => match self.heap.get(instance) {
+                Some(Object::Instance(object)) => Some((instance, object.structure)),
+                _ => None,
+            },
+            _ => None,
+        };
+        let (instance, structure) = match target {
+            Some(target) => target,
+            None => {
+                let message = format!(
+                    "Only instances have properties, got {}.",
+                    self.heap.type_name(&receiver)
+                );
+                return Err(self.runtime_error(&message));
+            }
+        };
+        let object = match self.heap.get(structure) {
+            Some(Object::Struct(object)) => object,
+            _ => return Err(self.runtime_error("Instance of an invalid struct")),
         };
 
-        match operation {
-            BinaryOperation::Add => self.stack.push(a + b),
-            BinaryOperation::Subtract => self.stack.push(a - b),
-            BinaryOperation::Multiply => self.stack.push(a * b),
-            BinaryOperation::Divide => self.stack.push(a / b),
+        let public = match object.field_index(name) {
+            Some(index) => object.fields[index].public,
+            None => match object.methods.get(&name) {
+                Some(method) => method.public,
+                None => {
+                    let message = format!(
+                        "Undefined property '{}'.",
+                        self.heap.get_str(name).unwrap_or_default()
+                    );
+                    return Err(self.runtime_error(&message));
+                }
+            },
         };
+        if !public && self.frame.module != object.module {
+            let message = format!(
+                "Cannot access private member '{}' of {}.",
+                self.heap.get_str(name).unwrap_or_default(),
+                self.heap.get_str(object.name).unwrap_or_default()
+            );
+            return Err(self.runtime_error(&message));
+        }
+        Ok((instance, object))
+    }
+
+    /// Read a field or a module member, or bind a method to the receiver
+    fn get_property(&mut self, receiver: Value, name: ObjRef) -> Result<Value, InterpretError> {
+        if let Value::Object(object) = receiver {
+            if let Some(Object::Module(module)) = self.heap.get(object) {
+                return self.module_member(module, name);
+            }
+        }
+
+        let (instance, structure) = self.property_target(receiver, name)?;
+        if let Some(index) = structure.field_index(name) {
+            return match self.heap.get(instance) {
+                Some(Object::Instance(instance)) => Ok(instance.fields[index]),
+                _ => Err(self.runtime_error("Invalid instance")),
+            };
+        }
+
+        let method = match structure.methods.get(&name) {
+            Some(method) => method.closure,
+            None => return Err(self.runtime_error("Undefined property")),
+        };
+        let bound = self.alloc(Object::BoundMethod(ObjBoundMethod { receiver, method }));
+        Ok(Value::Object(bound))
+    }
+
+    fn set_property(
+        &mut self,
+        receiver: Value,
+        name: ObjRef,
+        value: Value,
+    ) -> Result<(), InterpretError> {
+        let (instance, structure) = self.property_target(receiver, name)?;
+        let index = match structure.field_index(name) {
+            Some(index) => index,
+            None => {
+                let message = format!(
+                    "Can't assign to method '{}'.",
+                    self.heap.get_str(name).unwrap_or_default()
+                );
+                return Err(self.runtime_error(&message));
+            }
+        };
+        match self.heap.get_mut(instance) {
+            Some(Object::Instance(instance)) => {
+                instance.fields[index] = value;
+                Ok(())
+            }
+            _ => Err(self.runtime_error("Invalid instance")),
+        }
+    }
+
+    /// The upvalue at `index` of the closure being executed
+    fn frame_upvalue(&self, index: usize) -> Result<ObjRef, InterpretError> {
+        let upvalue = match self
+            .frame
+            .closure
+            .and_then(|closure| self.heap.get(closure))
+        {
+            Some(Object::Closure(closure)) => closure.upvalues.get(index).copied(),
+            _ => None,
+        };
+        match upvalue {
+            Some(upvalue) => Ok(upvalue),
+            None => Err(self.invalid_upvalue(index)),
+        }
+    }
+
+    /// Return the open upvalue for the stack slot, creating it if no closure has
+    /// captured the slot yet so closures capturing the same variable share it
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let mut position = self.open_upvalues.len();
+        for (i, upvalue) in self.open_upvalues.iter().enumerate().rev() {
+            match self.heap.get(*upvalue) {
+                Some(Object::Upvalue(ObjUpvalue::Open(open))) if *open == slot => return *upvalue,
+                Some(Object::Upvalue(ObjUpvalue::Open(open))) if *open < slot => break,
+                _ => position = i,
+            }
+        }
+
+        let upvalue = self.alloc(Object::Upvalue(ObjUpvalue::Open(slot)));
+        self.open_upvalues.insert(position, upvalue);
+        upvalue
+    }
+
+    /// Move the values of every open upvalue at or above the stack slot to the heap
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = match self.heap.get(*upvalue) {
+                Some(Object::Upvalue(ObjUpvalue::Open(slot))) => *slot,
+                _ => break,
+            };
+            if slot < last {
+                break;
+            }
+            let value = self.stack.get(slot).copied().unwrap_or(Value::Nil);
+            if let Some(Object::Upvalue(upvalue)) = self.heap.get_mut(*upvalue) {
+                *upvalue = ObjUpvalue::Closed(value);
+            }
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn invalid_upvalue(&self, index: usize) -> InterpretError {
+        self.runtime_error(&format!("Invalid upvalue index {}", index))
+    }
+
+    fn not_callable(&self, callee: Value) -> InterpretError {
+        let message = format!("Can't call a {}.", self.heap.type_name(&callee));
+        self.runtime_error(&message)
+    }
+
+    fn read_chunk(&mut self) -> Result<Chunk, InterpretError> {
+        match self.frame.bytecode.get_chunk(self.frame.ip) {
+            Some(chunk) => {
+                self.frame.ip += 1;
+                Ok(*chunk)
+            }
+            None => Err(self.runtime_error(&format!(
+                "Unexpected end of bytecode at offset {}",
+                self.frame.ip
+            ))),
+        }
+    }
+
+    /// Read a one chunk operand
+    fn read_operand(&mut self) -> Result<usize, InterpretError> {
+        Ok(usize::from(self.read_chunk()?))
+    }
+
+    fn read_short(&mut self) -> Result<usize, InterpretError> {
+        match self.frame.bytecode.get_short(self.frame.ip) {
+            Some(operand) => {
+                self.frame.ip += 2;
+                Ok(operand)
+            }
+            None => Err(self.truncated_operand()),
+        }
+    }
+
+    fn read_long(&mut self) -> Result<usize, InterpretError> {
+        match self.frame.bytecode.get_long(self.frame.ip) {
+            Some(operand) => {
+                self.frame.ip += 3;
+                Ok(operand)
+            }
+            None => Err(self.truncated_operand()),
+        }
+    }
+
+    fn truncated_operand(&self) -> InterpretError {
+        self.runtime_error(&format!(
+            "Unexpected end of bytecode at offset {}",
+            self.frame.ip
+        ))
+    }
+
+    /// Read a three chunk constant index operand and return the constant
+    fn read_constant(&mut self) -> Result<Value, InterpretError> {
+        let index = self.read_long()?;
+        self.constant(index)
+    }
+
+    fn constant(&self, index: usize) -> Result<Value, InterpretError> {
+        match self.frame.bytecode.get_constant(index) {
+            Some(constant) => Ok(*constant),
+            None => Err(self.runtime_error(&format!("Invalid constant index {}", index))),
+        }
+    }
+
+    /// Read a constant operand that must be an interned variable name
+    fn read_name(&mut self) -> Result<ObjRef, InterpretError> {
+        match self.read_constant()? {
+            Value::Object(name) if self.heap.get_str(name).is_some() => Ok(name),
+            _ => Err(self.runtime_error("Variable name must be a string constant")),
+        }
+    }
+
+    fn undefined_variable(&self, name: ObjRef) -> InterpretError {
+        let message = format!(
+            "Undefined variable '{}'.",
+            self.heap.get_str(name).unwrap_or_default()
+        );
+        self.runtime_error(&message)
+    }
+
+    fn invalid_slot(&self, slot: usize) -> InterpretError {
+        self.runtime_error(&format!("Invalid local slot {}", slot))
+    }
+
+    /// Pop `count` values, returned in the order they were pushed
+    fn pop_many(&mut self, count: usize) -> Result<Vec<Value>, InterpretError> {
+        match self.stack.len().checked_sub(count) {
+            Some(start) => Ok(self.stack.split_off(start)),
+            None => Err(self.runtime_error("Stack underflow")),
+        }
+    }
+
+    fn get_index(&mut self, target: Value, index: Value) -> Result<Value, InterpretError> {
+        let object = match target {
+            Value::Object(object) => self.heap.get(object),
+            _ => None,
+        };
+        match object {
+            Some(Object::List(list)) => {
+                let position = self.list_position(list.items.len(), index)?;
+                Ok(list.items[position])
+            }
+            // Missing keys read as nil so counting with `m[k] = (m[k] or 0) + 1` works
+            Some(Object::Map(map)) => Ok(map.get(index).unwrap_or(Value::Nil)),
+            Some(Object::String(string)) => {
+                let chars = string.chars.clone();
+                let count = chars.chars().count();
+                let position = self.list_position(count, index)?;
+                let mut buffer = [0; 4];
+                let c = chars.chars().nth(position).unwrap_or_default();
+                let string = self.intern(c.encode_utf8(&mut buffer));
+                Ok(Value::Object(string))
+            }
+            _ => {
+                let message = format!("Can't index into a {}.", self.heap.type_name(&target));
+                Err(self.runtime_error(&message))
+            }
+        }
+    }
+
+    fn set_index(
+        &mut self,
+        target: Value,
+        index: Value,
+        value: Value,
+    ) -> Result<(), InterpretError> {
+        let length = match target {
+            Value::Object(object) => match self.heap.get(object) {
+                Some(Object::List(list)) => Some(list.items.len()),
+                _ => None,
+            },
+            _ => None,
+        };
+        let position = match length {
+            Some(length) => Some(self.list_position(length, index)?),
+            None => None,
+        };
+
+        let object = match target {
+            Value::Object(object) => self.heap.get_mut(object),
+            _ => None,
+        };
+        match (object, position) {
+            (Some(Object::List(list)), Some(position)) => list.items[position] = value,
+            (Some(Object::Map(map)), _) => map.insert(index, value),
+            _ => {
+                let message = format!(
+                    "Can't assign to an index of a {}.",
+                    self.heap.type_name(&target)
+                );
+                return Err(self.runtime_error(&message));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that `index` is a whole number addressing one of `length` elements
+    fn list_position(&self, length: usize, index: Value) -> Result<usize, InterpretError> {
+        match index {
+            Value::Number(index) if index.fract() == 0.0 && index >= 0.0 => {
+                if (index as usize) < length {
+                    Ok(index as usize)
+                } else {
+                    let message = format!("Index {} out of range for length {}.", index, length);
+                    Err(self.runtime_error(&message))
+                }
+            }
+            Value::Number(index) => {
+                let message = format!("Index must be a non-negative integer, got {}.", index);
+                Err(self.runtime_error(&message))
+            }
+            index => {
+                let message = format!(
+                    "Index must be a number, got {}.",
+                    self.heap.type_name(&index)
+                );
+                Err(self.runtime_error(&message))
+            }
+        }
+    }
+
+    /// Start iterating over a value, which is the first half of the iterator protocol
+    fn get_iter(&mut self, iterable: Value) -> Result<Value, InterpretError> {
+        let object = match iterable {
+            Value::Object(object) => object,
+            _ => return Err(self.not_iterable(iterable)),
+        };
+        let iterator = match self.heap.get(object) {
+            Some(Object::Range(range)) => ObjIterator::Range {
+                next: range.start,
+                end: range.end,
+                inclusive: range.inclusive,
+            },
+            Some(Object::List(_)) => ObjIterator::List {
+                list: object,
+                index: 0,
+            },
+            Some(Object::Map(_)) => ObjIterator::MapKeys {
+                map: object,
+                index: 0,
+            },
+            Some(Object::String(_)) => ObjIterator::Chars {
+                string: object,
+                offset: 0,
+            },
+            Some(Object::Iterator(_)) => return Ok(iterable),
+            // A struct implements the protocol with an `iter` method returning either
+            // something with a `next` method or a value that is iterable itself
+            Some(Object::Instance(_)) => {
+                let iterator = match self.call_method(iterable, "iter")? {
+                    Some(iterator) => iterator,
+                    None => return Err(self.not_iterable(iterable)),
+                };
+                return match self.is_instance(iterator) {
+                    true => Ok(iterator),
+                    false => self.get_iter(iterator),
+                };
+            }
+            _ => return Err(self.not_iterable(iterable)),
+        };
+        let iterator = self.alloc(Object::Iterator(iterator));
+        Ok(Value::Object(iterator))
+    }
+
+    /// Advance an iterator, which is the second half of the iterator protocol.
+    /// Returns `None` once the iterator is exhausted.
+    fn iter_next(&mut self, iterator: ObjRef) -> Result<Option<Value>, InterpretError> {
+        // Iterators implemented by structs return `nil` once they are exhausted
+        if self.is_instance(Value::Object(iterator)) {
+            return match self.call_method(Value::Object(iterator), "next")? {
+                Some(Value::Nil) => Ok(None),
+                Some(item) => Ok(Some(item)),
+                None => Err(self.runtime_error("Iterator has no 'next' method.")),
+            };
+        }
+
+        let (item, advanced) = match self.heap.get(iterator) {
+            Some(Object::Iterator(ObjIterator::Range {
+                next,
+                end,
+                inclusive,
+            })) => {
+                let remaining = match inclusive {
+                    true => next <= end,
+                    false => next < end,
+                };
+                if !remaining {
+                    return Ok(None);
+                }
+                let advanced = ObjIterator::Range {
+                    next: next + 1.0,
+                    end: *end,
+                    inclusive: *inclusive,
+                };
+                (Value::Number(*next), advanced)
+            }
+            Some(Object::Iterator(ObjIterator::List { list, index })) => {
+                let item = match self.heap.get(*list) {
+                    Some(Object::List(list)) => list.items.get(*index).copied(),
+                    _ => None,
+                };
+                match item {
+                    Some(item) => {
+                        let advanced = ObjIterator::List {
+                            list: *list,
+                            index: index + 1,
+                        };
+                        (item, advanced)
+                    }
+                    None => return Ok(None),
+                }
+            }
+            Some(Object::Iterator(ObjIterator::MapKeys { map, index })) => {
+                let key = match self.heap.get(*map) {
+                    Some(Object::Map(map)) => map.entries.get(*index).map(|entry| entry.0),
+                    _ => None,
+                };
+                match key {
+                    Some(key) => {
+                        let advanced = ObjIterator::MapKeys {
+                            map: *map,
+                            index: index + 1,
+                        };
+                        (key, advanced)
+                    }
+                    None => return Ok(None),
+                }
+            }
+            Some(Object::Iterator(ObjIterator::Chars { string, offset })) => {
+                let c = match self.heap.get_str(*string) {
+                    Some(chars) => chars[*offset..].chars().next(),
+                    None => None,
+                };
+                match c {
+                    Some(c) => {
+                        let advanced = ObjIterator::Chars {
+                            string: *string,
+                            offset: offset + c.len_utf8(),
+                        };
+                        let mut buffer = [0; 4];
+                        let c = self.intern(c.encode_utf8(&mut buffer));
+                        (Value::Object(c), advanced)
+                    }
+                    None => return Ok(None),
+                }
+            }
+            _ => return Err(self.runtime_error("Expected an iterator")),
+        };
+
+        if let Some(Object::Iterator(state)) = self.heap.get_mut(iterator) {
+            *state = advanced;
+        }
+        Ok(Some(item))
+    }
+
+    fn is_instance(&self, value: Value) -> bool {
+        match value {
+            Value::Object(object) => matches!(self.heap.get(object), Some(Object::Instance(_))),
+            _ => false,
+        }
+    }
+
+    /// Call a method of an instance without arguments and run it to completion.
+    /// Returns `None` when the instance's struct has no method called `name`.
+    fn call_method(
+        &mut self,
+        instance: Value,
+        name: &str,
+    ) -> Result<Option<Value>, InterpretError> {
+        let name = self.intern(name);
+        let structure = match instance {
+            Value::Object(instance) => match self.heap.get(instance) {
+                Some(Object::Instance(instance)) => self.heap.get(instance.structure),
+                _ => None,
+            },
+            _ => None,
+        };
+        match structure {
+            Some(Object::Struct(structure)) if structure.methods.contains_key(&name) => {}
+            _ => return Ok(None),
+        }
+
+        let method = self.get_property(instance, name)?;
+        self.stack.push(method);
+        let depth = self.frames.len();
+        self.call_value(method, 0)?;
+        if self.frames.len() > depth {
+            self.run()?;
+        }
+        self.pop().map(Some)
+    }
+
+    fn not_iterable(&self, value: Value) -> InterpretError {
+        let message = format!(
+            "Value of type {} is not iterable.",
+            self.heap.type_name(&value)
+        );
+        self.runtime_error(&message)
+    }
+
+    fn pop(&mut self) -> Result<Value, InterpretError> {
+        match self.stack.pop() {
+            Some(element) => Ok(element),
+            None => Err(self.runtime_error("Stack underflow")),
+        }
+    }
+
+    /// Look at the value `distance` slots below the top of the stack
+    fn peek(&self, distance: usize) -> Result<Value, InterpretError> {
+        let index = self.stack.len().checked_sub(distance + 1);
+        match index.and_then(|index| self.stack.get(index)) {
+            Some(value) => Ok(*value),
+            None => Err(self.runtime_error("Stack underflow")),
+        }
+    }
+
+    fn runtime_error(&self, message: &str) -> InterpretError {
+        // The last chunk read belongs to the instruction being executed
+        let offset = self.frame.ip.saturating_sub(1);
+        let line = frame_line(&self.frame);
+        let mut diagnostic = Diagnostic::error(message, line);
+        if let Some(span) = self.frame.bytecode.get_span(offset) {
+            diagnostic = diagnostic.at(span.into(), line, span.column as usize);
+        }
+        if let Some(file) = self.module_file(self.frame.module) {
+            diagnostic = diagnostic.in_file(file);
+        }
+        InterpretError::RuntimeError {
+            diagnostics: vec![diagnostic],
+            trace: self.stack_trace(),
+        }
+    }
+
+    /// The active calls, innermost first, with runs of identical calls collapsed so deep
+    /// recursion doesn't bury the rest of the trace
+    fn stack_trace(&self) -> Vec<TraceFrame> {
+        let mut trace: Vec<TraceFrame> = Vec::new();
+        let frames = std::iter::once(&self.frame)
+            .chain(self.frames.iter().rev())
+            .filter_map(|frame| {
+                // The placeholder frame isn't a call
+                let closure = frame.closure?;
+                let function = match self.heap.get(closure) {
+                    Some(Object::Closure(closure)) => match self.heap.get(closure.function) {
+                        Some(Object::Function(function)) => function.name,
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                Some(TraceFrame {
+                    function: function
+                        .and_then(|name| self.heap.get_str(name))
+                        .map(str::to_string),
+                    file: self.module_file(frame.module).map(Path::to_path_buf),
+                    line: frame_line(frame),
+                    count: 1,
+                })
+            });
+        for frame in frames {
+            match trace.last_mut() {
+                Some(last)
+                    if last.function == frame.function
+                        && last.file == frame.file
+                        && last.line == frame.line =>
+                {
+                    last.count += 1
+                }
+                _ => trace.push(frame),
+            }
+        }
+        trace
+    }
+
+    fn module_file(&self, module: ObjRef) -> Option<&Path> {
+        match self.heap.get(module) {
+            Some(Object::Module(module)) => module.file.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn binary_op(&mut self, operation: BinaryOperation) -> Result<(), InterpretError> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        let result = self.binary_result(operation, a, b)?;
+        self.stack.push(result);
+        Ok(())
+    }
+
+    fn binary_result(
+        &mut self,
+        operation: BinaryOperation,
+        a: Value,
+        b: Value,
+    ) -> Result<Value, InterpretError> {
+        if let BinaryOperation::Add = operation {
+            if let (Value::Object(a), Value::Object(b)) = (a, b) {
+                if let (Some(a), Some(b)) = (self.heap.get_str(a), self.heap.get_str(b)) {
+                    let concatenated = [a, b].concat();
+                    return Ok(Value::Object(self.intern(&concatenated)));
+                }
+            }
+        }
+
+        let (a, b) = match (a, b) {
+            (Value::Number(a), Value::Number(b)) => (a, b),
+            (a, b) => {
+                let message = match operation {
+                    BinaryOperation::Add => "Operands must be two numbers or two strings",
+                    _ => "Operands must be numbers",
+                };
+                return Err(self.runtime_error(&format!(
+                    "{}, got {} and {}.",
+                    message,
+                    self.heap.type_name(&a),
+                    self.heap.type_name(&b)
+                )));
+            }
+        };
+
+        let result = match operation {
+            BinaryOperation::Add => Value::Number(a + b),
+            BinaryOperation::Subtract => Value::Number(a - b),
+            BinaryOperation::Multiply => Value::Number(a * b),
+            BinaryOperation::Divide => Value::Number(a / b),
+            BinaryOperation::Greater => Value::Bool(a > b),
+            BinaryOperation::Less => Value::Bool(a < b),
+        };
+        Ok(result)
     }
 }
 
@@ -74,61 +1606,44 @@ impl<T> Stack<T> {
     fn push(&mut self, item: T) {
         self.stack.push(item)
     }
-}
 
-impl<T: fmt::Display> fmt::Display for Stack<T> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for item in &self.stack {
-            write!(f, "[ {} ]", item)?;
+    fn peek(&self) -> Option<&T> {
+        self.stack.last()
+    }
+
+    fn len(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Remove and return the elements from `index` to the top
+    fn split_off(&mut self, index: usize) -> Vec<T> {
+        self.stack.split_off(index)
+    }
+
+    fn truncate(&mut self, length: usize) {
+        self.stack.truncate(length)
+    }
+
+    fn get(&self, index: usize) -> Option<&T> {
+        self.stack.get(index)
+    }
+
+    /// Returns false when there is no element at `index`
+    fn set(&mut self, index: usize, item: T) -> bool {
+        match self.stack.get_mut(index) {
+            Some(element) => {
+                *element = item;
+                true
+            }
+            None => false,
         }
-        Ok(())
     }
-}
 
+    fn clear(&mut self) {
+        self.stack.clear()
+    }
 
-//pub fn interpret(&mut self, source: &ByteCode) -> Result<(), InterpretError> {
-//    while self.ip < source.chunk_count() {
-//        if cfg!(feature = "debug_trace_execution") {
-//            println!("          {}", self.stack);
-//            disassemble_instruction(source, self.ip);
-//        };
-//
-//        let chunk = match source.get_chunk(self.ip) {
-//            Some(c) => c,
-//            None => panic!("Instruction pointer ({}) > chunk count", self.ip),
-//        };
-//        self.ip += 1;
-//        match OpCode::try_from(*chunk) {
-//            Ok(opcode) => match opcode {
-//                OpCode::Constant => {
-//                    let index = match source.get_chunk(self.ip) {
-//                        Some(i) => i,
-//                        None => panic!("Instruction pointer ({}) > chunk count", self.ip),
-//                    };
-//                    self.ip += 1;
-//                    let constant = match source.get_constant(*index) {
-//                        Some(c) => c,
-//                        None => panic!("Invalid constant index {}", chunk),
-//                    };
-//                    self.stack.push(*constant);
-//                }
-//                OpCode::Add => self.binary_op(BinaryOperation::Add),
-//                OpCode::Subtract => self.binary_op(BinaryOperation::Subtract),
-//                OpCode::Multiply => self.binary_op(BinaryOperation::Multiply),
-//                OpCode::Divide => self.binary_op(BinaryOperation::Divide),
-//                OpCode::Negate => {
-//                    if let Some(element) = self.stack.pop() {
-//                        self.stack.push(-element)
-//                    }
-//                }
-//                OpCode::Return => {
-//                    if let Some(element) = self.stack.pop() {
-//                        println!("{}", element)
-//                    }
-//                }
-//            },
-//            Err(_) => panic!("Unknown opcode {}", chunk),
-//        }
-//    }
-//    Ok(())
-//}
+    fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.stack.iter()
+    }
+}
@@ -0,0 +1,25 @@
+mod common;
+
+use std::ffi::OsStr;
+
+use common::{run_args, write_script};
+
+const USAGE: &str =
+    "Usage: raven [-O<level>] [run] [path]\n       raven [-O<level>] compile path [-o output]\n";
+
+#[test]
+fn missing_path() {
+    for arguments in [&["run"][..], &["compile"], &["-O1", "run"]] {
+        let output = run_args(arguments);
+        assert_eq!(output.code, Some(64), "{:?}", arguments);
+        assert_eq!(output.stderr, USAGE, "{:?}", arguments);
+    }
+}
+
+#[test]
+fn run_with_path() {
+    let path = write_script("cli/hello.rv", "print(\"hello\")");
+    let output = run_args(&[OsStr::new("run"), path.as_os_str()]);
+    assert_eq!(output.code, Some(0), "{}", output.stderr);
+    assert_eq!(output.stdout, "hello\n");
+}
@@ -0,0 +1,83 @@
+//! Helpers shared by the integration tests
+
+#![allow(dead_code)]
+
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use raven_lang::{compile_with_options, CompileOptions, Heap, ModuleLoader, ObjRef};
+
+/// What running a program with the `raven` binary printed, and how it exited
+pub struct Output {
+    pub stdout: String,
+    pub stderr: String,
+    pub code: Option<i32>,
+}
+
+/// Write `source` to a file named `name` in the test directory and return its path
+pub fn write_script(name: &str, source: &str) -> PathBuf {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).expect("could not create the test directory");
+    }
+    fs::write(&path, source).expect("could not write the test script");
+    path
+}
+
+/// Run the program in the file with the `raven` binary and the given arguments before
+/// the path
+pub fn run_file(path: &Path, arguments: &[&str]) -> Output {
+    let mut arguments: Vec<&OsStr> = arguments.iter().map(OsStr::new).collect();
+    arguments.push(path.as_os_str());
+    run_args(&arguments)
+}
+
+/// Run the `raven` binary with exactly these arguments
+pub fn run_args<S: AsRef<OsStr>>(arguments: &[S]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_raven-lang"))
+        .args(arguments)
+        .output()
+        .expect("could not run raven");
+    Output {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        code: output.status.code(),
+    }
+}
+
+/// Run `source` as a script named `name`
+pub fn run(name: &str, source: &str) -> Output {
+    run_file(&write_script(name, source), &[])
+}
+
+/// What `source` prints, failing the test if it doesn't run to the end
+pub fn output_of(name: &str, source: &str) -> String {
+    let output = run(name, source);
+    assert_eq!(output.code, Some(0), "{} failed:\n{}", name, output.stderr);
+    output.stdout
+}
+
+/// The programs in `tests/programs`, which compile and run without errors
+pub fn sample_programs() -> Vec<PathBuf> {
+    let directory = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let mut programs: Vec<PathBuf> = fs::read_dir(directory)
+        .expect("could not list the sample programs")
+        .map(|entry| entry.expect("could not list the sample programs").path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "rv"))
+        .collect();
+    programs.sort();
+    programs
+}
+
+/// Compile the program in the file at `path` into `heap`
+pub fn compile_file(path: &PathBuf, heap: &mut Heap, options: CompileOptions) -> ObjRef {
+    let source = fs::read_to_string(path).expect("could not read the program");
+    let mut loader = ModuleLoader::default();
+    loader.set_script_path(path);
+    match compile_with_options(&source, heap, &mut loader, options) {
+        Ok((script, _)) => script,
+        Err(diagnostics) => panic!("{} failed to compile: {:?}", path.display(), diagnostics),
+    }
+}
@@ -0,0 +1,144 @@
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+
+use raven_lang::{compile, compile_with_imports, Diagnostic, Heap, ModuleLoader};
+
+use common::{output_of, write_script};
+
+/// Diagnostics from compiling the script at `path`
+fn diagnostics(path: &PathBuf) -> Vec<Diagnostic> {
+    let source = fs::read_to_string(path).unwrap();
+    let mut loader = ModuleLoader::default();
+    loader.set_script_path(path);
+    match compile_with_imports(&source, &mut Heap::new(), &mut loader) {
+        Ok((_, warnings)) => warnings,
+        Err(diagnostics) => diagnostics,
+    }
+}
+
+/// Message, line and column of each error compiling `source` reports
+fn errors(source: &str) -> Vec<(String, usize, Option<usize>)> {
+    match compile(source, &mut Heap::new()) {
+        Ok(_) => Vec::new(),
+        Err(diagnostics) => diagnostics
+            .into_iter()
+            .map(|diagnostic| (diagnostic.message, diagnostic.line, diagnostic.column))
+            .collect(),
+    }
+}
+
+fn error(message: &str, line: usize, column: usize) -> (String, usize, Option<usize>) {
+    (message.to_string(), line, Some(column))
+}
+
+#[test]
+fn recovers_inside_arguments() {
+    assert_eq!(
+        errors("print(1, match 1 { _ => { let = 3\n 0 } })"),
+        vec![error("Expect expression.", 1, 27)]
+    );
+    assert_eq!(
+        errors("print(1, f(2, [3, {let}]), 4)\nprint(5)"),
+        vec![error("Expect expression.", 1, 20)]
+    );
+    let source = "function f() {\n    print(1, match 1 { _ => { let = 3 } })\n}\nf()";
+    assert_eq!(errors(source), vec![error("Expect expression.", 2, 31)]);
+}
+
+#[test]
+fn skips_stray_braces_at_top_level() {
+    assert_eq!(
+        errors("if x == { print(1) }"),
+        vec![error("Expect ':' after map key.", 1, 20)]
+    );
+    assert_eq!(
+        errors("function f( {\n let a = 1\n}"),
+        vec![error("Expect parameter name.", 1, 13)]
+    );
+    assert_eq!(
+        errors("while (1 { let a = 1 }"),
+        vec![error("Expect ')' after expression.", 1, 10)]
+    );
+    assert_eq!(errors("}"), vec![error("Expect expression.", 1, 1)]);
+    assert_eq!(
+        errors("if x == { print(1) }\nlet = 2"),
+        vec![
+            error("Expect ':' after map key.", 1, 20),
+            error("Expect variable name.", 2, 5)
+        ]
+    );
+}
+
+/// A function with `count` locals that passes a match binding to a call
+fn with_locals(count: usize) -> String {
+    let locals: String = (0..count)
+        .map(|i| format!("    let v{} = {}\n", i, i))
+        .collect();
+    format!(
+        "function f() {{\n{}    print(1, match 2 {{ n => n }})\n}}\nf()\n",
+        locals
+    )
+}
+
+#[test]
+fn locals_above_temporaries() {
+    assert_eq!(
+        errors(&with_locals(254)),
+        vec![error("Too many local variables in scope.", 256, 22)]
+    );
+    assert_eq!(
+        output_of("diagnostics/locals.rv", &with_locals(250)),
+        "1 2\n"
+    );
+}
+
+#[test]
+fn import_cycle_notes() {
+    write_script("diagnostics/cycle/b.rv", "import \"c\"");
+    write_script("diagnostics/cycle/c.rv", "import \"a\"");
+    let path = write_script("diagnostics/cycle/a.rv", "import \"b\"");
+
+    let diagnostics = diagnostics(&path);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "Importing 'a' creates a cycle.");
+    assert_eq!(
+        diagnostics[0].notes,
+        vec![
+            "a.rv imports b.rv.",
+            "b.rv imports c.rv.",
+            "c.rv imports a.rv.",
+            "In module 'c' imported at b.rv:1:8.",
+            "In module 'b' imported at a.rv:1:8.",
+        ]
+    );
+}
+
+#[test]
+fn module_error_notes_import_site() {
+    write_script(
+        "diagnostics/module/bad.rv",
+        "let x = 1\nx = 2\nlet y = 1\ny = 3",
+    );
+    let path = write_script("diagnostics/module/main.rv", "print(1)\nimport \"bad\"");
+
+    let diagnostics = diagnostics(&path);
+    let messages: Vec<&str> = diagnostics
+        .iter()
+        .map(|diagnostic| diagnostic.message.as_str())
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            "Cannot assign to immutable variable 'x'.",
+            "Cannot assign to immutable variable 'y'.",
+        ]
+    );
+    for diagnostic in &diagnostics {
+        assert_eq!(
+            diagnostic.notes,
+            vec!["In module 'bad' imported at main.rv:2:8."]
+        );
+    }
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use raven_lang::{compile, Heap};
+
+use common::output_of;
+
+/// Messages of the errors compiling `source` reports
+fn errors(source: &str) -> Vec<String> {
+    match compile(source, &mut Heap::new()) {
+        Ok(_) => Vec::new(),
+        Err(diagnostics) => diagnostics
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect(),
+    }
+}
+
+#[test]
+fn string_literal_in_embedded_expression() {
+    let source = "
+let name = \"Ann\"
+print(\"hi {\"Mr. \" + name}!\")
+print(\"hi {name + \"!\"}\")
+print(\"{\"{\"nested\"}\"}\")
+";
+    assert_eq!(
+        output_of("interpolation_literal.rv", source),
+        "hi Mr. Ann!\nhi Ann!\nnested\n"
+    );
+}
+
+#[test]
+fn several_embedded_expressions() {
+    let source = "print(\"{1}{2} and {1 + 2}\")";
+    assert_eq!(output_of("interpolation_several.rv", source), "12 and 3\n");
+}
+
+#[test]
+fn output_shaped_like_a_trace() {
+    let source = "print(\"{1000} items\")\nprint(\"          {2} indented\")";
+    assert_eq!(
+        output_of("interpolation_trace.rv", source),
+        "1000 items\n          2 indented\n"
+    );
+}
+
+#[test]
+fn empty_braces() {
+    let expected = vec!["Expect expression in string interpolation.".to_string()];
+    assert_eq!(errors("print(\"a {} b\")"), expected);
+    assert_eq!(errors("print(\"a {  } b {1}\")"), expected);
+}
+
+#[test]
+fn incomplete_expression() {
+    assert_eq!(errors("print(\"x {1 + } y\")"), vec!["Expect expression."]);
+    assert_eq!(
+        errors("print(\"x {1 + } y {2}\")"),
+        vec!["Expect expression."]
+    );
+}
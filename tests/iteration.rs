@@ -0,0 +1,78 @@
+mod common;
+
+use common::{output_of, run};
+
+const COUNTER: &str = "
+struct Counter {
+    count
+    function iter() { return self }
+    function next() {
+        if self.count >= 3 { return nil }
+        self.count = self.count + 1
+        return self.count
+    }
+}
+";
+
+#[test]
+fn struct_with_iter_and_next() {
+    let source = format!("{}for x in Counter(0) {{ print(x) }}", COUNTER);
+    assert_eq!(output_of("iteration_counter.rv", &source), "1\n2\n3\n");
+}
+
+#[test]
+fn struct_iter_returning_a_list() {
+    let source = "
+struct Bag {
+    items
+    function iter() { return self.items }
+}
+for x in Bag([1, \"a\", true]) { print(x) }
+";
+    assert_eq!(output_of("iteration_bag.rv", source), "1\na\ntrue\n");
+}
+
+#[test]
+fn break_and_continue_over_a_struct() {
+    let source = format!(
+        "{}
+function f() {{
+    let mutable total = 0
+    for x in Counter(0) {{
+        if x == 1 {{ continue }}
+        if x == 3 {{ break }}
+        total += x
+    }}
+    return total
+}}
+print(f())
+",
+        COUNTER
+    );
+    assert_eq!(output_of("iteration_break.rv", &source), "2\n");
+}
+
+#[test]
+fn struct_without_iter() {
+    let output = run(
+        "iteration_not_iterable.rv",
+        "struct Point { x }\nfor p in Point(1) { print(p) }",
+    );
+    assert_eq!(output.code, Some(70));
+    assert!(output
+        .stderr
+        .contains("Value of type instance is not iterable."));
+}
+
+#[test]
+fn iterator_without_next() {
+    let source = "
+struct Broken {
+    function iter() { return self }
+}
+for x in Broken() { print(x) }
+";
+    let output = run("iteration_no_next.rv", source);
+    assert_eq!(output.code, Some(70));
+    assert!(output.stderr.contains("Iterator has no 'next' method."));
+}
@@ -0,0 +1,144 @@
+mod common;
+
+use raven_lang::{
+    compile, disassemble_instruction, optimize, verify, Heap, ObjRef, Object, OpCode,
+    OptimizationLevel, Value,
+};
+
+use common::{run_file, sample_programs, write_script};
+
+/// The script compiled from `source` and optimized at `level`
+fn optimized(source: &str, level: OptimizationLevel) -> (Heap, ObjRef) {
+    let mut heap = Heap::new();
+    let (script, _) = compile(source, &mut heap).unwrap();
+    optimize(&mut heap, script, level);
+    assert_eq!(verify(&heap, script), Ok(()));
+    (heap, script)
+}
+
+/// Opcodes of the function's instructions, in order
+fn opcodes(heap: &Heap, function: ObjRef) -> Vec<OpCode> {
+    let Some(Object::Function(function)) = heap.get(function) else {
+        panic!("expected a function");
+    };
+    let bytecode = &function.bytecode;
+    let mut opcodes = Vec::new();
+    let mut offset = 0;
+    while offset < bytecode.chunk_count() {
+        opcodes.push(OpCode::try_from(*bytecode.get_chunk(offset).unwrap()).unwrap());
+        offset = disassemble_instruction(bytecode, heap, offset);
+    }
+    opcodes
+}
+
+#[test]
+fn folds_constant_expression() {
+    let (heap, script) = optimized("let seconds = 60 * 60 * 24", OptimizationLevel::Basic);
+    let Some(Object::Function(function)) = heap.get(script) else {
+        panic!("expected a function");
+    };
+    let bytecode = &function.bytecode;
+    assert_eq!(
+        bytecode.get_chunks(),
+        &vec![
+            OpCode::Constant.into(),
+            0,
+            OpCode::DefineGlobal.into(),
+            0,
+            0,
+            1,
+            OpCode::Nil.into(),
+            OpCode::Return.into(),
+        ]
+    );
+    assert_eq!(bytecode.get_constants()[0], Value::Number(86400.0));
+    let name = match bytecode.get_constants()[1] {
+        Value::Object(name) => heap.get_str(name),
+        _ => None,
+    };
+    assert_eq!(name, Some("seconds"));
+}
+
+#[test]
+fn removes_negate_pairs_only_when_aggressive() {
+    let source = "let y = 2\nlet x = -(-y)";
+    let negates = |level| {
+        let (heap, script) = optimized(source, level);
+        opcodes(&heap, script)
+            .into_iter()
+            .filter(|opcode| *opcode == OpCode::Negate)
+            .count()
+    };
+    assert_eq!(negates(OptimizationLevel::None), 2);
+    assert_eq!(negates(OptimizationLevel::Basic), 2);
+    assert_eq!(negates(OptimizationLevel::Aggressive), 0);
+}
+
+#[test]
+fn deduplicates_constants() {
+    let (heap, script) = optimized(
+        "print(\"a\", \"a\", 1, 1, 1 + 0, \"b\")",
+        OptimizationLevel::Basic,
+    );
+    let Some(Object::Function(function)) = heap.get(script) else {
+        panic!("expected a function");
+    };
+    // `print` and one each of "a", 1 and "b"
+    assert_eq!(function.bytecode.get_constants().len(), 4);
+}
+
+#[test]
+fn jumps_survive_dead_code_removal() {
+    // The dead call and the jumps after `continue` and `break` sit between the loop's
+    // jumps and their targets
+    let source = "
+let mutable total = 0
+for i in 0..10 {
+    if i == 2 { continue }
+    if i == 4 {
+        break
+        print(\"after break\")
+    }
+    total += i
+}
+print(total)
+";
+    let (before, script) = optimized(source, OptimizationLevel::None);
+    let unoptimized = opcodes(&before, script).len();
+    let (after, script) = optimized(source, OptimizationLevel::Basic);
+    assert_eq!(opcodes(&after, script).len(), unoptimized - 6);
+    let Some(Object::Function(function)) = after.get(script) else {
+        panic!("expected a function");
+    };
+    assert!(!function
+        .bytecode
+        .get_constants()
+        .iter()
+        .any(|constant| matches!(constant, Value::Object(string) if after.get_str(*string) == Some("after break"))));
+
+    let path = write_script("optimizer_dead_code.rv", source);
+    for level in ["-O0", "-O1", "-O2"] {
+        let output = run_file(&path, &[level]);
+        assert_eq!(output.code, Some(0), "{}", output.stderr);
+        assert_eq!(output.stdout, "4\n");
+    }
+}
+
+#[test]
+fn sample_programs_print_the_same_at_every_level() {
+    for path in sample_programs() {
+        let unoptimized = run_file(&path, &["-O0"]);
+        assert_eq!(unoptimized.code, Some(0), "{}", unoptimized.stderr);
+        for level in ["-O1", "-O2"] {
+            let output = run_file(&path, &[level]);
+            assert_eq!(output.code, Some(0), "{}", output.stderr);
+            assert_eq!(
+                output.stdout,
+                unoptimized.stdout,
+                "{} at {}",
+                path.display(),
+                level
+            );
+        }
+    }
+}
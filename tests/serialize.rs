@@ -0,0 +1,227 @@
+mod common;
+
+use std::path::Path;
+
+use raven_lang::{
+    compile_with_imports, deserialize, serialize, Heap, LoadError, ModuleLoader, ObjRef, Object,
+    Value, FORMAT_VERSION, MAGIC,
+};
+
+use common::write_script;
+
+const PROGRAM: &str = "
+import \"geometry\"
+import area from \"geometry\"
+
+let mutable total = 0
+function add(n) {
+    total += n
+    return function () { return total + n }
+}
+print(add(2)(), \"text {total}\", nil, true, false, -0.5)
+print(area(geometry.Square(2)))
+";
+
+const GEOMETRY: &str = "
+public struct Square {
+    public side
+    public function scaled(k) { return Square(self.side * k) }
+}
+public function area(square) { return square.side * square.side }
+";
+
+/// The program compiled into `heap`, along with its serialized form
+fn compiled(heap: &mut Heap) -> (ObjRef, Vec<u8>) {
+    write_script("serialize/geometry.rv", GEOMETRY);
+    let path = write_script("serialize/main.rv", PROGRAM);
+    let mut loader = ModuleLoader::default();
+    loader.set_script_path(&path);
+    let (script, _) = compile_with_imports(PROGRAM, heap, &mut loader).unwrap();
+    let bytes = serialize(heap, script, Some(&path)).unwrap();
+    (script, bytes)
+}
+
+/// Assert two functions have the same code, comparing constants by contents
+fn assert_same_function(a: (&Heap, ObjRef), b: (&Heap, ObjRef)) {
+    let (Some(Object::Function(first)), Some(Object::Function(second))) =
+        (a.0.get(a.1), b.0.get(b.1))
+    else {
+        panic!("expected two functions");
+    };
+    let name = |heap: &Heap, name: Option<ObjRef>| {
+        name.and_then(|name| heap.get_str(name).map(str::to_string))
+    };
+    assert_eq!(name(a.0, first.name), name(b.0, second.name));
+    assert_eq!(first.arity, second.arity);
+    assert_eq!(first.upvalue_count, second.upvalue_count);
+    assert_eq!(first.bytecode.get_chunks(), second.bytecode.get_chunks());
+    assert_eq!(
+        first.bytecode.get_line_runs(),
+        second.bytecode.get_line_runs()
+    );
+    assert_eq!(
+        first.bytecode.get_span_runs(),
+        second.bytecode.get_span_runs()
+    );
+
+    let constants = first
+        .bytecode
+        .get_constants()
+        .iter()
+        .zip(second.bytecode.get_constants());
+    assert_eq!(
+        first.bytecode.get_constants().len(),
+        second.bytecode.get_constants().len()
+    );
+    for (x, y) in constants {
+        match (x, y) {
+            (Value::Object(x), Value::Object(y)) => match (a.0.get(*x), b.0.get(*y)) {
+                (Some(Object::String(x)), Some(Object::String(y))) => assert_eq!(x.chars, y.chars),
+                (Some(Object::Function(_)), Some(Object::Function(_))) => {
+                    assert_same_function((a.0, *x), (b.0, *y))
+                }
+                (Some(Object::Module(x)), Some(Object::Module(y))) => {
+                    assert_eq!(a.0.get_str(x.name), b.0.get_str(y.name));
+                    assert_eq!(x.file, y.file);
+                    assert_same_function((a.0, x.script.unwrap()), (b.0, y.script.unwrap()));
+                }
+                _ => panic!("constants of different types"),
+            },
+            (Value::Number(x), Value::Number(y)) => assert_eq!(x.to_bits(), y.to_bits()),
+            (x, y) => assert_eq!(x, y),
+        }
+    }
+}
+
+fn load_error(bytes: &[u8]) -> LoadError {
+    match deserialize(bytes, &mut Heap::new()) {
+        Ok(_) => panic!("loaded a malformed file"),
+        Err(error) => error,
+    }
+}
+
+/// Replace the `u32` at `offset`
+fn set_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[test]
+fn round_trip() {
+    let mut heap = Heap::new();
+    let (script, bytes) = compiled(&mut heap);
+
+    let mut loaded_heap = Heap::new();
+    let loaded = deserialize(&bytes, &mut loaded_heap).unwrap();
+    assert_same_function((&heap, script), (&loaded_heap, loaded.script));
+    assert_eq!(
+        loaded.source.as_deref().and_then(Path::file_name),
+        Some("main.rv".as_ref())
+    );
+
+    // Both imports of the module load the same one
+    let modules: Vec<ObjRef> = match loaded_heap.get(loaded.script) {
+        Some(Object::Function(function)) => function
+            .bytecode
+            .get_constants()
+            .iter()
+            .filter_map(|constant| match constant {
+                Value::Object(object) => match loaded_heap.get(*object) {
+                    Some(Object::Module(_)) => Some(*object),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    assert!(modules.len() >= 2);
+    assert!(modules.iter().all(|module| *module == modules[0]));
+
+    let again = serialize(&loaded_heap, loaded.script, loaded.source.as_deref()).unwrap();
+    assert_eq!(again, bytes);
+}
+
+#[test]
+fn bad_magic() {
+    let (_, mut bytes) = compiled(&mut Heap::new());
+    bytes[0] = b'X';
+    assert_eq!(
+        load_error(&bytes),
+        LoadError {
+            message: "Not a Raven bytecode file.".to_string(),
+            offset: 0,
+        }
+    );
+}
+
+#[test]
+fn wrong_format_version() {
+    let (_, mut bytes) = compiled(&mut Heap::new());
+    let version = MAGIC.len();
+    bytes[version..version + 2].copy_from_slice(&(FORMAT_VERSION - 1).to_le_bytes());
+    assert_eq!(
+        load_error(&bytes),
+        LoadError {
+            message: format!(
+                "Unsupported format version {}, expected {}.",
+                FORMAT_VERSION - 1,
+                FORMAT_VERSION
+            ),
+            offset: version + 2,
+        }
+    );
+}
+
+#[test]
+fn truncated_at_every_length() {
+    let (_, bytes) = compiled(&mut Heap::new());
+    for length in 0..bytes.len() {
+        let error = load_error(&bytes[..length]);
+        assert!(error.offset <= length, "{:?} at length {}", error, length);
+    }
+}
+
+#[test]
+fn trailing_data() {
+    let (_, mut bytes) = compiled(&mut Heap::new());
+    let end = bytes.len();
+    bytes.push(0);
+    assert_eq!(
+        load_error(&bytes),
+        LoadError {
+            message: "Unexpected data after the script.".to_string(),
+            offset: end,
+        }
+    );
+}
+
+#[test]
+fn oversized_lengths() {
+    let (_, bytes) = compiled(&mut Heap::new());
+    // Magic, version and the flag saying a source path follows
+    let source_length = MAGIC.len() + 2 + 1;
+    let source = u32::from_le_bytes(bytes[source_length..source_length + 4].try_into().unwrap());
+    // The script has no name, its flag is followed by the arity and upvalue count
+    let chunk_count = source_length + 4 + source as usize + 1 + 4 + 4;
+    let chunks = u32::from_le_bytes(bytes[chunk_count..chunk_count + 4].try_into().unwrap());
+    let line_runs = chunk_count + 4 + chunks as usize;
+
+    for offset in [source_length, chunk_count, line_runs] {
+        for length in [u32::MAX, bytes.len() as u32] {
+            let mut bytes = bytes.clone();
+            set_u32(&mut bytes, offset, length);
+            let error = load_error(&bytes);
+            assert!(error.offset <= bytes.len(), "{:?}", error);
+        }
+    }
+
+    let mut bytes = bytes.clone();
+    set_u32(&mut bytes, chunk_count, u32::MAX);
+    assert_eq!(
+        load_error(&bytes),
+        LoadError {
+            message: "Unexpected end of file.".to_string(),
+            offset: chunk_count + 4,
+        }
+    );
+}
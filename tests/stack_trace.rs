@@ -0,0 +1,55 @@
+use raven_lang::{TraceFrame, VirtualMachine};
+
+fn frame(function: Option<&str>, line: usize, count: usize) -> TraceFrame {
+    TraceFrame {
+        function: function.map(str::to_string),
+        file: None,
+        line,
+        count,
+    }
+}
+
+#[test]
+fn recursion_is_collapsed() {
+    let source = "
+function down(n) {
+    return down(n + 1)
+}
+function start() { return down(0) }
+start()
+";
+    let mut vm = VirtualMachine::with_max_call_depth(64);
+    let error = vm.interpret(source).unwrap_err();
+    assert_eq!(
+        error.trace(),
+        [
+            frame(Some("down"), 3, 61),
+            frame(Some("start"), 5, 1),
+            frame(None, 6, 1),
+        ]
+    );
+    assert!(error
+        .to_string()
+        .contains("  in down() at line 3 (repeated 61 times)\n  in start() at line 5"));
+}
+
+#[test]
+fn calls_from_different_lines_are_kept() {
+    let source = "
+function fail(n) {
+    if n == 0 { return nil + 1 }
+    return fail(n - 1)
+}
+fail(2)
+";
+    let mut vm = VirtualMachine::new();
+    let error = vm.interpret(source).unwrap_err();
+    assert_eq!(
+        error.trace(),
+        [
+            frame(Some("fail"), 3, 1),
+            frame(Some("fail"), 4, 2),
+            frame(None, 6, 1),
+        ]
+    );
+}
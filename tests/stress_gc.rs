@@ -0,0 +1,105 @@
+//! Programs run while collecting garbage on every allocation, so an object that isn't
+//! rooted is freed while still in use. Run with `cargo test --features stress_gc`.
+
+#![cfg(feature = "stress_gc")]
+
+mod common;
+
+use std::fs;
+
+use raven_lang::VirtualMachine;
+
+use common::{run_file, write_script};
+
+const PROGRAM: &str = "
+import \"shapes\"
+
+function make_counter() {
+    let mutable count = 0
+    function increment() {
+        count += 1
+        return \"count {count}\"
+    }
+    return increment
+}
+let counter = make_counter()
+counter()
+print(counter())
+
+struct Range3 {
+    current
+    function iter() { return self }
+    function next() {
+        if self.current >= 3 { return nil }
+        self.current = self.current + 1
+        return \"item \" + \"{self.current}\"
+    }
+}
+for item in Range3(0) { print(item) }
+
+let mutable words = \"\"
+for c in \"abc\" { words = words + c + c }
+print(words, [words, {\"key\": words}])
+
+let square = shapes.Square(3)
+print(square.area(), shapes.describe(square))
+
+struct Pair { public left public right }
+function label(pair) {
+    return match pair {
+        Pair { left: \"a\", right: \"b\" } => \"ab\",
+        Pair { left: \"b\", right: \"a\" } => \"ba\",
+        _ => \"other\"
+    }
+}
+print(label(Pair(\"a\", \"b\")), label(Pair(\"b\", \"a\")), label(Pair(\"a\", \"a\")))
+";
+
+const SHAPES: &str = "
+public struct Square {
+    public side
+    public function area() { return self.side * self.side }
+}
+public function describe(shape) { return \"square of {shape.side}\" }
+";
+
+const EXPECTED: &str = "count 2
+item 1
+item 2
+item 3
+aabbcc [aabbcc, {key: aabbcc}]
+9 square of 3
+ab ba other
+";
+
+#[test]
+fn output() {
+    write_script("stress_gc/output/shapes.rv", SHAPES);
+    let path = write_script("stress_gc/output/main.rv", PROGRAM);
+    let output = run_file(&path, &[]);
+    assert_eq!(output.code, Some(0), "{}", output.stderr);
+    assert_eq!(output.stdout, EXPECTED);
+}
+
+#[test]
+fn heap_stats() {
+    write_script("stress_gc/stats/shapes.rv", SHAPES);
+    let path = write_script("stress_gc/stats/main.rv", PROGRAM);
+    let source = fs::read_to_string(&path).unwrap();
+
+    let mut vm = VirtualMachine::new();
+    vm.set_script_path(&path);
+    vm.interpret(&source).unwrap();
+    vm.collect_garbage();
+    let first = vm.heap_stats();
+    assert!(first.collections > 1);
+
+    // Running again replaces the globals, so everything else the first run allocated
+    // is garbage
+    vm.interpret(&source).unwrap();
+    vm.collect_garbage();
+    let second = vm.heap_stats();
+    assert!(second.collections > first.collections);
+    assert_eq!(second.live_objects, first.live_objects);
+    assert_eq!(second.bytes_allocated, first.bytes_allocated);
+}
@@ -0,0 +1,223 @@
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+
+use raven_lang::{
+    disassemble_instruction, serialize, verify, CompileOptions, Heap, ObjRef, Object, OpCode,
+    Value, BYTECODE_EXTENSION,
+};
+
+use common::{compile_file, run_file, sample_programs, write_script, Output};
+
+const PLAIN: CompileOptions = CompileOptions {
+    superinstructions: false,
+};
+
+const FUSED: CompileOptions = CompileOptions {
+    superinstructions: true,
+};
+
+/// Loops, comparisons and jumps, including jumps that land right after a comparison
+/// and so must keep it apart from the jump that follows
+const JUMPS: &str = "
+function sum(n) {
+    let mutable i = 0
+    let mutable total = 0
+    while i < n {
+        total = total + 3
+        i = i + 1
+    }
+    return total
+}
+
+function classify(x, y) {
+    if x == y { return \"same\" }
+    if x > y { return \"greater\" }
+    if x < y and y < 10 { return \"less\" }
+    if x < 0 or y == 10 { return \"edge\" }
+    return \"far\"
+}
+
+function walk(n) {
+    let mutable steps = \"\"
+    let mutable x = 0
+    while x < n {
+        x = x + 1
+        let mutable y = 0
+        while y < n {
+            y = y + 1
+            if y == 2 { continue }
+            if x + y > 6 { break }
+            steps = steps + \"{x}{y} \"
+        }
+        if x == 3 { continue }
+        steps = steps + \"| \"
+    }
+    return steps
+}
+
+function chain(low, high) {
+    let mutable a = low
+    let b = high
+    let mutable count = 0
+    while (a < b) == true {
+        a = a + 1
+        count = count + 0.5
+    }
+    let c = count > 2 or count == 1
+    return \"{count} {c} {a == b}\"
+}
+
+let mutable i = 0
+let mutable sum_here = 0
+while i < 5 {
+    sum_here = sum_here + i
+    i = i + 1
+}
+print(sum(10), sum_here, i = i + 1)
+print(classify(1, 1), classify(3, 1), classify(1, 3), classify(-2, 20), classify(0, 10), classify(5, 40))
+print(walk(4))
+print(chain(1, 5), chain(4, 4))
+for k in 0..4 { if k > 1 { print(k) } }
+";
+
+/// Compile the program with the given options and write it as a compiled file in the
+/// test directory
+fn compile_to_file(path: &PathBuf, options: CompileOptions, suffix: &str) -> PathBuf {
+    let mut heap = Heap::new();
+    let script = compile_file(path, &mut heap, options);
+    assert_eq!(
+        verify(&heap, script),
+        Ok(()),
+        "{} failed to verify",
+        path.display()
+    );
+    let bytes = serialize(&heap, script, Some(path)).expect("could not serialize");
+    let stem = path.file_stem().unwrap().to_string_lossy();
+    let name = format!(
+        "superinstructions/{}.{}.{}",
+        stem, suffix, BYTECODE_EXTENSION
+    );
+    let output = write_script(&name, "");
+    fs::write(&output, bytes).expect("could not write the compiled file");
+    output
+}
+
+/// Run the program compiled with and without superinstructions
+fn run_both(path: &PathBuf) -> (Output, Output) {
+    let plain = run_file(&compile_to_file(path, PLAIN, "plain"), &[]);
+    let fused = run_file(&compile_to_file(path, FUSED, "fused"), &[]);
+    (plain, fused)
+}
+
+fn assert_same(path: &PathBuf) {
+    let (plain, fused) = run_both(path);
+    assert_eq!(plain.code, fused.code, "{}", path.display());
+    assert_eq!(plain.stdout, fused.stdout, "{}", path.display());
+    assert_eq!(plain.stderr, fused.stderr, "{}", path.display());
+}
+
+/// Opcodes of the function and every function nested in it
+fn opcodes(heap: &Heap, function: ObjRef, opcodes: &mut Vec<OpCode>) {
+    let Some(Object::Function(function)) = heap.get(function) else {
+        panic!("expected a function");
+    };
+    let bytecode = &function.bytecode;
+    let mut offset = 0;
+    while offset < bytecode.chunk_count() {
+        opcodes.push(OpCode::try_from(*bytecode.get_chunk(offset).unwrap()).unwrap());
+        offset = disassemble_instruction(bytecode, heap, offset);
+    }
+    for constant in bytecode.get_constants() {
+        if let Value::Object(object) = constant {
+            if let Some(Object::Function(_)) = heap.get(*object) {
+                self::opcodes(heap, *object, opcodes);
+            }
+        }
+    }
+}
+
+fn compiled_opcodes(path: &PathBuf, options: CompileOptions) -> Vec<OpCode> {
+    let mut heap = Heap::new();
+    let script = compile_file(path, &mut heap, options);
+    let mut found = Vec::new();
+    opcodes(&heap, script, &mut found);
+    found
+}
+
+const SUPERINSTRUCTIONS: [OpCode; 5] = [
+    OpCode::AddConstant,
+    OpCode::IncrementLocal,
+    OpCode::LessJumpIfFalse,
+    OpCode::GreaterJumpIfFalse,
+    OpCode::EqualJumpIfFalse,
+];
+
+#[test]
+fn jumps_use_every_superinstruction() {
+    let path = write_script("superinstructions/opcodes.rv", JUMPS);
+    let plain = compiled_opcodes(&path, PLAIN);
+    let fused = compiled_opcodes(&path, FUSED);
+    for opcode in SUPERINSTRUCTIONS {
+        assert!(
+            !plain.contains(&opcode),
+            "{:?} without superinstructions",
+            opcode
+        );
+        assert!(
+            fused.contains(&opcode),
+            "no {:?} with superinstructions",
+            opcode
+        );
+    }
+    assert!(fused.len() < plain.len());
+}
+
+#[test]
+fn jumps_print_the_same() {
+    let path = write_script("superinstructions/jumps.rv", JUMPS);
+    let (plain, fused) = run_both(&path);
+    assert_eq!(plain.code, Some(0), "{}", plain.stderr);
+    assert_eq!(
+        plain.stdout,
+        "30 10 6\n\
+         same greater less edge edge far\n\
+         11 13 14 | 21 23 24 | 31 33 41 | \n\
+         2 false true 0 false true\n\
+         2\n\
+         3\n"
+    );
+    assert_eq!(fused.code, plain.code);
+    assert_eq!(fused.stdout, plain.stdout);
+    assert_eq!(fused.stderr, plain.stderr);
+}
+
+#[test]
+fn runtime_errors_are_the_same() {
+    let sources = [
+        (
+            "compare",
+            "let mutable i = 0\nwhile i < \"ten\" {\n    i = i + 1\n}\n",
+        ),
+        (
+            "add",
+            "function f(x) {\n    let mutable y = x\n    y = y + 1\n    return y\n}\nf(nil)\n",
+        ),
+        ("greater", "if [] > 1 {\n    print(1)\n}\n"),
+    ];
+    for (name, source) in sources {
+        let path = write_script(&format!("superinstructions/{}.rv", name), source);
+        let (plain, fused) = run_both(&path);
+        assert_eq!(plain.code, Some(70), "{} did not fail", name);
+        assert_eq!(fused.code, plain.code, "{}", name);
+        assert_eq!(fused.stderr, plain.stderr, "{}", name);
+    }
+}
+
+#[test]
+fn samples_print_the_same() {
+    for program in sample_programs() {
+        assert_same(&program);
+    }
+}
@@ -0,0 +1,220 @@
+mod common;
+
+use std::rc::Rc;
+
+use raven_lang::{
+    optimize, verify, ByteCode, Chunk, CompileOptions, Heap, ObjFunction, ObjRef, Object, OpCode,
+    OptimizationLevel, Value, VerifyError,
+};
+
+use common::{compile_file, sample_programs};
+
+fn op(opcode: OpCode) -> Chunk {
+    opcode.into()
+}
+
+/// The top-level code of a script with the given instruction stream and constants
+fn script(heap: &mut Heap, chunks: Vec<Chunk>, constants: Vec<Value>) -> ObjRef {
+    let bytecode = ByteCode::from_parts(chunks, constants, vec![(0, 1)], Vec::new());
+    heap.alloc(Object::Function(ObjFunction {
+        name: None,
+        arity: 0,
+        upvalue_count: 0,
+        bytecode: Rc::new(bytecode),
+    }))
+}
+
+fn verify_chunks(chunks: Vec<Chunk>, constants: Vec<Value>) -> Result<(), VerifyError> {
+    let mut heap = Heap::new();
+    let script = script(&mut heap, chunks, constants);
+    verify(&heap, script)
+}
+
+fn error(message: &str, offset: usize) -> Result<(), VerifyError> {
+    Err(VerifyError {
+        message: message.to_string(),
+        function: None,
+        offset,
+    })
+}
+
+#[test]
+fn valid_code() {
+    let chunks = vec![op(OpCode::Constant), 0, op(OpCode::Return)];
+    assert_eq!(verify_chunks(chunks, vec![Value::Number(1.0)]), Ok(()));
+}
+
+#[test]
+fn jump_past_the_end() {
+    let chunks = vec![op(OpCode::Jump), 0, 16, op(OpCode::Nil), op(OpCode::Return)];
+    assert_eq!(
+        verify_chunks(chunks, Vec::new()),
+        error("Execution runs past the end of the code.", 19)
+    );
+}
+
+#[test]
+fn loop_before_the_start() {
+    let chunks = vec![op(OpCode::Loop), 0, 4, op(OpCode::Nil), op(OpCode::Return)];
+    assert_eq!(
+        verify_chunks(chunks, Vec::new()),
+        error("Loop jumps before the code.", 0)
+    );
+}
+
+#[test]
+fn jump_into_an_instruction() {
+    // Lands on the operand of `Constant`
+    let chunks = vec![
+        op(OpCode::Jump),
+        0,
+        1,
+        op(OpCode::Constant),
+        0,
+        op(OpCode::Return),
+    ];
+    assert_eq!(
+        verify_chunks(chunks, vec![Value::Nil]),
+        error("Jump into the middle of an instruction.", 4)
+    );
+}
+
+#[test]
+fn stack_depth_mismatch_at_merge() {
+    // Only the path that falls through pushes an extra value before the jumps meet
+    let chunks = vec![
+        op(OpCode::True),
+        op(OpCode::JumpIfFalse),
+        0,
+        1,
+        op(OpCode::Nil),
+        op(OpCode::Return),
+    ];
+    assert_eq!(
+        verify_chunks(chunks, Vec::new()),
+        error("Stack depth is 3 on one path and 2 on another.", 5)
+    );
+}
+
+#[test]
+fn stack_underflow() {
+    let chunks = vec![op(OpCode::Add), op(OpCode::Return)];
+    assert_eq!(
+        verify_chunks(chunks, Vec::new()),
+        error("Needs 2 values but the stack has 1.", 0)
+    );
+}
+
+#[test]
+fn missing_constant() {
+    let chunks = vec![op(OpCode::Constant), 1, op(OpCode::Return)];
+    assert_eq!(
+        verify_chunks(chunks, vec![Value::Nil]),
+        error("Constant 1 doesn't exist.", 0)
+    );
+
+    let chunks = vec![op(OpCode::ConstantLong), 0, 1, 0, op(OpCode::Return)];
+    assert_eq!(
+        verify_chunks(chunks, vec![Value::Nil]),
+        error("Constant 256 doesn't exist.", 0)
+    );
+}
+
+#[test]
+fn constant_of_the_wrong_type() {
+    let chunks = vec![op(OpCode::GetGlobal), 0, 0, 0, op(OpCode::Return)];
+    assert_eq!(
+        verify_chunks(chunks, vec![Value::Number(1.0)]),
+        error("Name constant must be a string.", 0)
+    );
+}
+
+#[test]
+fn local_outside_the_frame() {
+    let chunks = vec![op(OpCode::GetLocal), 1, op(OpCode::Return)];
+    assert_eq!(
+        verify_chunks(chunks, Vec::new()),
+        error("Local slot 1 is outside the frame.", 0)
+    );
+
+    let chunks = vec![op(OpCode::Nil), op(OpCode::SetLocal), 2, op(OpCode::Return)];
+    assert_eq!(
+        verify_chunks(chunks, Vec::new()),
+        error("Local slot 2 is outside the frame.", 1)
+    );
+}
+
+#[test]
+fn missing_upvalue() {
+    let chunks = vec![op(OpCode::GetUpvalue), 0, op(OpCode::Return)];
+    assert_eq!(
+        verify_chunks(chunks, Vec::new()),
+        error("Upvalue 0 doesn't exist.", 0)
+    );
+}
+
+#[test]
+fn truncated_instruction() {
+    let chunks = vec![op(OpCode::Nil), op(OpCode::Jump), 0];
+    assert_eq!(
+        verify_chunks(chunks, Vec::new()),
+        error("Truncated Jump instruction.", 1)
+    );
+}
+
+#[test]
+fn unknown_opcode() {
+    assert_eq!(
+        verify_chunks(vec![255], Vec::new()),
+        error("Unknown opcode 255.", 0)
+    );
+}
+
+#[test]
+fn nested_function_is_verified() {
+    let mut heap = Heap::new();
+    let name = heap.intern("broken");
+    let bytecode = ByteCode::from_parts(
+        vec![op(OpCode::GetLocal), 5, op(OpCode::Return)],
+        Vec::new(),
+        vec![(0, 1)],
+        Vec::new(),
+    );
+    let function = heap.alloc(Object::Function(ObjFunction {
+        name: Some(name),
+        arity: 0,
+        upvalue_count: 0,
+        bytecode: Rc::new(bytecode),
+    }));
+    let chunks = vec![op(OpCode::Closure), 0, 0, 0, op(OpCode::Return)];
+    let script = script(&mut heap, chunks, vec![Value::Object(function)]);
+    assert_eq!(
+        verify(&heap, script),
+        Err(VerifyError {
+            message: "Local slot 5 is outside the frame.".to_string(),
+            function: Some("broken".to_string()),
+            offset: 0,
+        })
+    );
+}
+
+#[test]
+fn sample_programs_pass() {
+    let plain = CompileOptions {
+        superinstructions: false,
+    };
+    for options in [CompileOptions::default(), plain] {
+        for level in [
+            OptimizationLevel::None,
+            OptimizationLevel::Basic,
+            OptimizationLevel::Aggressive,
+        ] {
+            for path in sample_programs() {
+                let mut heap = Heap::new();
+                let script = compile_file(&path, &mut heap, options);
+                optimize(&mut heap, script, level);
+                assert_eq!(verify(&heap, script), Ok(()), "{}", path.display());
+            }
+        }
+    }
+}